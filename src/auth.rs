@@ -0,0 +1,104 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use once_cell::sync::Lazy;
+
+const TOKEN_BYTES: usize = 32;
+
+static TOKEN_PATH: Lazy<Option<PathBuf>> = Lazy::new(get_token_path);
+
+// Generates a fresh token and stores it in a file only readable by the current user.
+pub fn generate_token() -> io::Result<String> {
+    let path = token_path()?;
+    write_new_token(path)
+}
+
+pub fn read_token() -> io::Result<String> {
+    let path = token_path()?;
+    let token = fs::read_to_string(path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!(
+                "Cannot read auth token from {} ({}), is topspin running?",
+                path.display(),
+                err
+            ),
+        )
+    })?;
+    Ok(token.trim().to_string())
+}
+
+// Compares tokens without short-circuiting on the first mismatched byte.
+pub fn verify_token(expected: &str, presented: &str) -> bool {
+    let expected = expected.as_bytes();
+    let presented = presented.trim().as_bytes();
+    if expected.len() != presented.len() {
+        return false;
+    }
+    expected
+        .iter()
+        .zip(presented.iter())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+fn write_new_token(path: &Path) -> io::Result<String> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // `mode` only applies to newly created files.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(token.as_bytes())?;
+
+    Ok(token)
+}
+
+fn token_path() -> io::Result<&'static Path> {
+    TOKEN_PATH
+        .as_deref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Cannot locate auth token file"))
+}
+
+fn get_token_path() -> Option<PathBuf> {
+    std::env::var("TOPSPIN_TOKEN")
+        .map(Into::into)
+        .ok()
+        .or_else(|| {
+            dirs::home_dir().map(|mut home| {
+                home.push(".config/topspin.token");
+                home
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_file() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("topspin-test-{}.token", std::process::id()));
+
+        let token = write_new_token(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let stored = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(token.len(), TOKEN_BYTES * 2);
+        assert!(verify_token(&token, &stored));
+        assert!(verify_token(&token, &format!("{}\r\n", stored)));
+        assert!(!verify_token(&token, &token[1..]));
+        assert!(!verify_token(&token, ""));
+    }
+}
@@ -1,9 +1,25 @@
+use std::path::PathBuf;
+
 use druid::Selector;
 
 use crate::model::RunResponse;
+use crate::persist::Commands;
 
 pub const RUN_RESPONSES: Selector<RunResponse> = Selector::new("channel.run_response");
 pub const SAVE_TO_FILE: Selector<()> = Selector::new("channel.save_to_file");
+// The config file, or the file of the project with the given root, was
+// changed on disk.
+pub const CONFIG_CHANGED: Selector<(Option<PathBuf>, Commands)> =
+    Selector::new("channel.config_changed");
+// The config file on disk could not be read or parsed.
+pub const CONFIG_ERROR: Selector<String> = Selector::new("channel.config_error");
 
 pub const STDOUT_TAG: u8 = 1;
 pub const STDERR_TAG: u8 = 2;
+pub const ERROR_TAG: u8 = 3;
+// Frame tags below are only sent to v2 clients.
+pub const HELLO_TAG: u8 = 4;
+pub const STARTED_TAG: u8 = 5;
+pub const EXITED_TAG: u8 = 6;
+
+pub const HELLO_PREFIX: &str = "TOPSPIN/";
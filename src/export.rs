@@ -0,0 +1,172 @@
+use std::str::FromStr;
+
+use serde_json::json;
+
+use crate::persist::CommandEntry;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExportFormat {
+    Procfile,
+    // A script running every command until interrupted
+    Sh,
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "procfile" => Ok(ExportFormat::Procfile),
+            "sh" => Ok(ExportFormat::Sh),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!(
+                "Unknown export format {}, expected procfile, sh or json",
+                s
+            )),
+        }
+    }
+}
+
+pub fn export(entries: &[(String, CommandEntry)], format: ExportFormat) -> Result<String, String> {
+    let mut output = String::new();
+    match format {
+        ExportFormat::Procfile => {
+            for (alias, entry) in entries {
+                output.push_str(&format!("{}: {}\n", alias, shell_line(alias, entry)?));
+            }
+        }
+        ExportFormat::Sh => {
+            output.push_str("#!/bin/sh\n");
+            output.push_str("# Stops every command when interrupted.\n");
+            output.push_str("trap 'kill 0' INT TERM\n\n");
+            for (alias, entry) in entries {
+                output.push_str(&format!("# {}\n", alias));
+                output.push_str(&format!("({}) &\n", shell_line(alias, entry)?));
+            }
+            output.push_str("\nwait\n");
+        }
+        ExportFormat::Json => {
+            let commands = entries
+                .iter()
+                .map(|(alias, entry)| {
+                    let (command, args) = program(alias, entry)?;
+                    Ok(json!({
+                        "alias": alias,
+                        "command": command,
+                        "args": args,
+                        "working_dir": entry.working_dir,
+                        "env": entry.env,
+                    }))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            let json = serde_json::to_string_pretty(&commands).map_err(|err| err.to_string())?;
+            output.push_str(&json);
+            output.push('\n');
+        }
+    }
+    Ok(output)
+}
+
+// Runs the command the way topspin does: in its working directory, with its
+// environment added.
+fn shell_line(alias: &str, entry: &CommandEntry) -> Result<String, String> {
+    let mut line = String::new();
+    if let Some(ref dir) = entry.working_dir {
+        line.push_str(&format!("cd {} && ", shell_words::quote(dir)));
+    }
+    line.push_str("exec ");
+    if !entry.env.is_empty() {
+        let env = entry
+            .env
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value));
+        line.push_str(&format!("env {} ", shell_words::join(env)));
+    }
+    let (command, args) = program(alias, entry)?;
+    line.push_str(&shell_words::join(std::iter::once(command).chain(args)));
+    Ok(line)
+}
+
+fn program(alias: &str, entry: &CommandEntry) -> Result<(String, Vec<String>), String> {
+    entry
+        .program()
+        .map_err(|err| format!("{} of {}", err, alias))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::Limits;
+
+    fn entries() -> Vec<(String, CommandEntry)> {
+        let mut env = indexmap::IndexMap::new();
+        env.insert("GREETING".to_string(), "it's \"fine\"".to_string());
+        vec![
+            (
+                "web".to_string(),
+                CommandEntry {
+                    command: "npm".to_string(),
+                    args: Some("start".to_string()),
+                    working_dir: Some("/srv/my app".to_string()),
+                    group: None,
+                    env: indexmap::IndexMap::new(),
+                    shell: None,
+                    limits: Limits::default(),
+                    ports: vec![],
+                },
+            ),
+            (
+                "hello".to_string(),
+                CommandEntry {
+                    command: "sh".to_string(),
+                    args: Some(r#"-c 'echo "$GREETING" > out.txt'"#.to_string()),
+                    working_dir: None,
+                    group: Some("misc".to_string()),
+                    env,
+                    shell: None,
+                    limits: Limits::default(),
+                    ports: vec![],
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_export_procfile() {
+        let procfile = export(&entries(), ExportFormat::Procfile).unwrap();
+        assert_eq!(
+            procfile,
+            r#"web: cd '/srv/my app' && exec npm start
+hello: exec env 'GREETING=it'\''s "fine"' sh -c 'echo "$GREETING" > out.txt'
+"#
+        );
+
+        // Arguments and variables round-trip through the shell quoting.
+        let words = shell_words::split(procfile.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(
+            words,
+            vec![
+                "hello:",
+                "exec",
+                "env",
+                "GREETING=it's \"fine\"",
+                "sh",
+                "-c",
+                "echo \"$GREETING\" > out.txt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_export_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(&export(&entries(), ExportFormat::Json).unwrap()).unwrap();
+        assert_eq!(json[0]["working_dir"], "/srv/my app");
+        assert_eq!(
+            json[1]["args"],
+            json!(["-c", "echo \"$GREETING\" > out.txt"])
+        );
+        assert_eq!(json[1]["env"]["GREETING"], "it's \"fine\"");
+    }
+}
@@ -0,0 +1,396 @@
+use std::io::Write;
+use std::path::Path;
+
+use indexmap::IndexMap;
+use regex::Regex;
+
+use crate::persist::{config_path, dump_entries, load_entries, CommandEntry, Limits};
+
+// Files commands can be imported from, told apart by their names.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Source {
+    // `name: command` lines
+    Procfile,
+    // `scripts` of a package.json, run with npm
+    PackageJson,
+    // Makefile targets, run with make
+    Makefile,
+    // `command` of the services in a docker-compose file, run as they are
+    Compose,
+}
+
+impl Source {
+    pub fn detect(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let is_yaml = name.ends_with(".yml") || name.ends_with(".yaml");
+        if name.starts_with("Procfile") {
+            Some(Source::Procfile)
+        } else if name == "package.json" {
+            Some(Source::PackageJson)
+        } else if name == "Makefile" || name == "makefile" || name == "GNUmakefile" {
+            Some(Source::Makefile)
+        } else if is_yaml && (name.starts_with("docker-compose") || name.starts_with("compose")) {
+            Some(Source::Compose)
+        } else {
+            None
+        }
+    }
+}
+
+// Commands defined in the file, run from the directory of the file.
+pub fn import_file(path: &Path) -> Result<Vec<(String, CommandEntry)>, Box<dyn std::error::Error>> {
+    let source = Source::detect(path).ok_or_else(|| {
+        format!(
+            "Cannot import from {}, expected a Procfile, package.json, Makefile or docker-compose.yml",
+            path.display()
+        )
+    })?;
+    let contents = std::fs::read_to_string(path)?;
+    let mut entries = parse(source, &contents)?;
+
+    let dir = path.canonicalize()?;
+    let dir = dir.parent().unwrap_or(&dir).to_string_lossy().into_owned();
+    for (_, entry) in entries.iter_mut() {
+        entry.working_dir = Some(dir.clone());
+    }
+    Ok(entries)
+}
+
+// Previews the commands of the file and adds the new ones to the config once
+// confirmed. Returns the exit code.
+pub fn run_import(
+    path: &Path,
+    group: Option<String>,
+    confirmed: bool,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    let existing = load_entries()?.unwrap_or_default();
+    let imported = import_file(path)?;
+    let width = imported.iter().map(|(alias, _)| alias.len()).max();
+
+    println!("Commands found in {}:", path.display());
+    let mut added = vec![];
+    for (alias, mut entry) in imported {
+        let line = format!("{} {}", entry.command, entry.args.as_deref().unwrap_or(""));
+        let skipped = existing.get(&alias).is_some();
+        println!(
+            "  {:width$}  {}{}",
+            alias,
+            line.trim_end(),
+            if skipped {
+                "  (skipped, alias in use)"
+            } else {
+                ""
+            },
+            width = width.unwrap_or(0)
+        );
+        if !skipped {
+            entry.group = group.clone();
+            added.push((alias, entry));
+        }
+    }
+    if added.is_empty() {
+        println!("Nothing to import");
+        return Ok(1);
+    }
+
+    let config_path = config_path().ok_or("Cannot locate the config file")?;
+    if !confirmed {
+        print!(
+            "Add {} commands to {}? [y/N] ",
+            added.len(),
+            config_path.display()
+        );
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !["y", "yes"].contains(&answer.trim().to_lowercase().as_str()) {
+            return Ok(1);
+        }
+    }
+    let count = added.len();
+    dump_entries(existing.into_iter().chain(added))?;
+    println!("Added {} commands to {}", count, config_path.display());
+    Ok(0)
+}
+
+fn parse(source: Source, contents: &str) -> Result<Vec<(String, CommandEntry)>, String> {
+    match source {
+        Source::Procfile => Ok(parse_procfile(contents)),
+        Source::PackageJson => parse_package_json(contents),
+        Source::Makefile => Ok(parse_makefile(contents)),
+        Source::Compose => Ok(parse_compose(contents)),
+    }
+}
+
+fn parse_procfile(contents: &str) -> Vec<(String, CommandEntry)> {
+    let process = Regex::new(r"^([A-Za-z0-9_-]+):\s*(.+)$").unwrap();
+    contents
+        .lines()
+        .filter_map(|line| process.captures(line.trim()))
+        .map(|captures| (captures[1].to_string(), command_line(&captures[2])))
+        .collect()
+}
+
+fn parse_package_json(contents: &str) -> Result<Vec<(String, CommandEntry)>, String> {
+    let package: serde_json::Value =
+        serde_json::from_str(contents).map_err(|err| format!("Invalid package.json: {}", err))?;
+    let scripts = match package
+        .get("scripts")
+        .and_then(|scripts| scripts.as_object())
+    {
+        Some(scripts) => scripts,
+        None => return Ok(vec![]),
+    };
+    // `prestart` and the like run along with `start`.
+    let is_hook = |name: &str| {
+        ["pre", "post"].iter().any(|prefix| {
+            name.strip_prefix(prefix)
+                .filter(|script| scripts.contains_key(*script))
+                .is_some()
+        })
+    };
+    Ok(scripts
+        .keys()
+        .filter(|name| !is_hook(name))
+        .map(|name| {
+            let entry = CommandEntry {
+                command: "npm".to_string(),
+                args: Some(shell_words::join(["run", name.as_str()])),
+                working_dir: None,
+                group: None,
+                env: IndexMap::new(),
+                shell: None,
+                limits: Limits::default(),
+                ports: vec![],
+            };
+            (name.clone(), entry)
+        })
+        .collect())
+}
+
+fn parse_makefile(contents: &str) -> Vec<(String, CommandEntry)> {
+    // Rules with a single target, leaving out special targets, pattern rules
+    // and `:=` assignments.
+    let rule = Regex::new(r"^([A-Za-z0-9][A-Za-z0-9_.-]*)\s*:($|[^=])").unwrap();
+    let mut entries: Vec<(String, CommandEntry)> = vec![];
+    for captures in contents.lines().filter_map(|line| rule.captures(line)) {
+        let target = captures[1].to_string();
+        if entries.iter().any(|(alias, _)| *alias == target) {
+            continue;
+        }
+        let entry = CommandEntry {
+            command: "make".to_string(),
+            args: Some(shell_words::quote(&target).into_owned()),
+            working_dir: None,
+            group: None,
+            env: IndexMap::new(),
+            shell: None,
+            limits: Limits::default(),
+            ports: vec![],
+        };
+        entries.push((target, entry));
+    }
+    entries
+}
+
+// A small reader for the `services.<name>.command` keys only, which are
+// either a string or a list.
+fn parse_compose(contents: &str) -> Vec<(String, CommandEntry)> {
+    let mut entries = vec![];
+    let mut in_services = false;
+    let mut service_indent = None;
+    let mut service: Option<String> = None;
+    // Indentation of a `command:` key followed by a block list, and its items.
+    let mut list: Option<(usize, Vec<String>)> = None;
+
+    for line in contents.lines() {
+        let text = line.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+        if let Some((list_indent, items)) = list.as_mut() {
+            if indent > *list_indent && text.starts_with('-') {
+                items.push(unquote(text[1..].trim()).to_string());
+                continue;
+            }
+        }
+        if let (Some((_, items)), Some(ref name)) = (list.take(), &service) {
+            if let Some(entry) = command_list(items) {
+                entries.push((name.clone(), entry));
+            }
+        }
+
+        if indent == 0 {
+            in_services = text == "services:";
+            service = None;
+            continue;
+        }
+        if !in_services {
+            continue;
+        }
+        let indent_of_services = *service_indent.get_or_insert(indent);
+        if indent <= indent_of_services {
+            service = text.strip_suffix(':').map(|name| unquote(name).to_string());
+            continue;
+        }
+        let name = match service {
+            Some(ref name) => name,
+            None => continue,
+        };
+        let value = match text.strip_prefix("command:") {
+            Some(value) => value.trim(),
+            None => continue,
+        };
+        if value.is_empty() {
+            list = Some((indent, vec![]));
+        } else if value.starts_with('[') {
+            let items = serde_json::from_str::<Vec<String>>(value).unwrap_or_else(|_| {
+                value
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .split(',')
+                    .map(|item| unquote(item.trim()).to_string())
+                    .collect()
+            });
+            entries.extend(command_list(items).map(|entry| (name.clone(), entry)));
+        } else {
+            entries.push((name.clone(), command_line(unquote(value))));
+        }
+    }
+    if let (Some((_, items)), Some(name)) = (list, service) {
+        entries.extend(command_list(items).map(|entry| (name, entry)));
+    }
+    entries
+}
+
+fn unquote(text: &str) -> &str {
+    for quote in &['"', '\''] {
+        if text.len() >= 2 && text.starts_with(*quote) && text.ends_with(*quote) {
+            return &text[1..text.len() - 1];
+        }
+    }
+    text
+}
+
+// A command line is run through `sh -c` when it needs a shell.
+fn command_line(line: &str) -> CommandEntry {
+    let line = line.trim();
+    let words = shell_words::split(line).ok().filter(|words| {
+        let needs_shell = ["&&", "||", "|", ";", "$", ">", "<", "`", "*"]
+            .iter()
+            .any(|token| line.contains(token));
+        let sets_env = words.first().filter(|word| word.contains('=')).is_some();
+        !words.is_empty() && !needs_shell && !sets_env
+    });
+    match words {
+        Some(words) => command_list(words).expect("not empty"),
+        None => CommandEntry {
+            command: "sh".to_string(),
+            args: Some(shell_words::join(["-c", line])),
+            working_dir: None,
+            group: None,
+            env: IndexMap::new(),
+            shell: None,
+            limits: Limits::default(),
+            ports: vec![],
+        },
+    }
+}
+
+fn command_list(mut words: Vec<String>) -> Option<CommandEntry> {
+    if words.is_empty() {
+        return None;
+    }
+    let command = words.remove(0);
+    Some(CommandEntry {
+        command,
+        args: Some(shell_words::join(words)).filter(|args| !args.is_empty()),
+        working_dir: None,
+        group: None,
+        env: IndexMap::new(),
+        shell: None,
+        limits: Limits::default(),
+        ports: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(entries: Vec<(String, CommandEntry)>) -> Vec<String> {
+        entries
+            .into_iter()
+            .map(|(alias, entry)| {
+                format!(
+                    "{}: {} {}",
+                    alias,
+                    entry.command,
+                    entry.args.unwrap_or_default()
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let procfile =
+            "web: bundle exec rails s -p $PORT\n# comment\nworker: bundle exec sidekiq\n";
+        assert_eq!(
+            commands(parse(Source::Procfile, procfile).unwrap()),
+            vec![
+                "web: sh -c 'bundle exec rails s -p $PORT'",
+                "worker: bundle exec sidekiq",
+            ]
+        );
+
+        let package = r#"{"scripts": {"prestart": "tsc", "start": "node .", "test": "jest"}}"#;
+        assert_eq!(
+            commands(parse(Source::PackageJson, package).unwrap()),
+            vec!["start: npm run start", "test: npm run test"]
+        );
+
+        let makefile = ".PHONY: build\nCC := gcc\nbuild: main.o\n\t$(CC) main.o\n%.o: %.c\ntest:\n";
+        assert_eq!(
+            commands(parse(Source::Makefile, makefile).unwrap()),
+            vec!["build: make build", "test: make test"]
+        );
+
+        assert_eq!(
+            Source::detect(Path::new("app/docker-compose.dev.yml")),
+            Some(Source::Compose)
+        );
+        assert_eq!(Source::detect(Path::new("Cargo.toml")), None);
+    }
+
+    #[test]
+    fn test_parse_compose() {
+        let compose = r#"version: "3"
+services:
+  web:
+    image: node
+    command: npm start
+  worker:
+    command: ["celery", "-A", "app worker"]
+  db:
+    image: postgres
+  cron:
+    command:
+      - sh
+      - -c
+      - "sleep 60 && ./job"
+volumes:
+  data:
+    command: not a service
+"#;
+        assert_eq!(
+            commands(parse(Source::Compose, compose).unwrap()),
+            vec![
+                "web: npm start",
+                "worker: celery -A 'app worker'",
+                "cron: sh -c 'sleep 60 && ./job'",
+            ]
+        );
+    }
+}
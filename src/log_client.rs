@@ -1,37 +1,644 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::convert::TryInto;
+use std::fmt;
 use std::io::{self, Read, StderrLock, StdoutLock, Write};
-use std::mem::size_of;
 use std::net::TcpStream;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::constants::{STDERR_TAG, STDOUT_TAG};
+use regex::bytes::Regex;
+use serde::Serialize;
 
-pub fn run_log_client(alias: &str) -> io::Result<()> {
+use crate::auth::read_token;
+use crate::constants::{ERROR_TAG, EXITED_TAG, HELLO_TAG, STARTED_TAG, STDERR_TAG, STDOUT_TAG};
+use crate::model::{signal_name, CommandStatus};
+use crate::proc_stats::{format_bytes, local_url};
+use crate::protocol::{
+    timestamp_millis, Version, EXIT_CODE, EXIT_SIGNAL, V1_HEADER_LEN, V2_HEADER_LEN,
+};
+
+// ANSI colours used to tell commands apart when following several of them.
+const PREFIX_COLORS: [u8; 6] = [36, 33, 32, 35, 34, 31];
+
+#[derive(Debug, Copy, Clone)]
+pub struct ClientOptions {
+    // Wait for commands which are not running yet.
+    pub wait: bool,
+    // Stay attached when commands exit, streaming their next runs.
+    pub follow_restarts: bool,
+    // Skip outputs rather than being disconnected when falling behind.
+    pub lossy: bool,
+    // Ask for one line per frame.
+    pub lines: bool,
+    pub format: Format,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Format {
+    // Outputs as they are, prefixed with the alias when following several commands.
+    Text,
+    // One JSON object per line.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown format: {} (expected text or json)", s)),
+        }
+    }
+}
+
+// Client side filters, applied to output frames once decoded.
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    // Only print lines matching the pattern.
+    pub pattern: Option<Regex>,
+    // Print the lines not matching the pattern instead.
+    pub invert: bool,
+    pub skip_stdout: bool,
+    pub skip_stderr: bool,
+    // Number of lines printed before and after each matching line.
+    pub context: usize,
+}
+
+// Returns the exit code of the streamed command. When following several
+// commands, it is the exit code of the first one (in the given order) that failed.
+pub fn run_log_client(
+    aliases: &[String],
+    options: ClientOptions,
+    filter: &LogFilter,
+) -> io::Result<i32> {
+    let token = read_token()?;
+    let options = ClientOptions {
+        // Patterns are matched line by line.
+        lines: options.lines || filter.pattern.is_some(),
+        ..options
+    };
+    if let ([alias], Format::Text) = (aliases, options.format) {
+        let stream = connect(alias, &token, options)?;
+        return stream_logs(stream, options, filter).map(ExitReason::exit_code);
+    }
+
+    // Prefixed and JSON outputs are printed line by line.
+    let options = ClientOptions {
+        lines: true,
+        ..options
+    };
+    let width = aliases.iter().map(|a| a.trim().len()).max().unwrap_or(0);
+    let colored = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    let handles: Vec<_> = aliases
+        .iter()
+        .enumerate()
+        .map(|(i, alias)| {
+            let color = if colored {
+                Some(PREFIX_COLORS[i % PREFIX_COLORS.len()])
+            } else {
+                None
+            };
+            let prefix = Prefix::new(alias.trim(), width, color);
+            let alias = alias.trim().to_string();
+            let stream = connect(&alias, &token, options);
+            let filter = filter.clone();
+            thread::spawn(move || {
+                let exit = stream.and_then(|stream| match options.format {
+                    Format::Text => follow_logs(stream, &prefix, options, &filter),
+                    Format::Json => json_logs(stream, &alias, options, &filter),
+                });
+                match exit {
+                    Ok(reason) => reason.exit_code(),
+                    Err(err) => {
+                        let _ = prefix.write_line(&mut io::stderr().lock(), err.to_string());
+                        1
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut code = 0;
+    for handle in handles {
+        let exit_code = handle.join().unwrap_or(1);
+        if code == 0 {
+            code = exit_code;
+        }
+    }
+    Ok(code)
+}
+
+fn connect(alias: &str, token: &str, options: ClientOptions) -> io::Result<TcpStream> {
+    let mut handshake = String::new();
+    if options.wait {
+        handshake.push_str("Wait: true\r\n");
+    }
+    if options.follow_restarts {
+        handshake.push_str("Follow-Restarts: true\r\n");
+    }
+    if options.lossy {
+        handshake.push_str("Overflow: drop\r\n");
+    }
+    if options.lines {
+        handshake.push_str("Framing: lines\r\n");
+    }
+    open(alias, token, &handshake)
+}
+
+// Sends the hello and headers, `headers` are `Name: value` lines on top of the
+// token and alias.
+fn open(alias: &str, token: &str, headers: &str) -> io::Result<TcpStream> {
     let mut stream = TcpStream::connect("127.0.0.1:9527")?;
-    stream.write(alias.trim().as_bytes())?;
-    stream.write(b"\r\n")?;
-    stream_logs(stream)
+    let handshake = format!(
+        "{}\r\nToken: {}\r\nAlias: {}\r\n{}\r\n",
+        Version::LATEST.hello(),
+        token,
+        alias.trim(),
+        headers
+    );
+    stream.write_all(handshake.as_bytes())?;
+    Ok(stream)
 }
 
-fn stream_logs<R: Read>(mut input_stream: R) -> io::Result<()> {
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub pattern: Option<String>,
+    // How far back the time range starts and ends.
+    pub since: Option<Duration>,
+    pub until: Option<Duration>,
+    pub limit: usize,
+}
+
+// Prints the recent output lines of a command, as kept by the running
+// instance. Returns the number of lines found.
+pub fn run_search(alias: &str, search: &SearchOptions, format: Format) -> io::Result<usize> {
+    let token = read_token()?;
+    let now = SystemTime::now();
+    let ago = |age: Duration| timestamp_millis(now.checked_sub(age).unwrap_or(UNIX_EPOCH));
+    let mut headers = format!("Request: search\r\nLimit: {}\r\n", search.limit);
+    if let Some(ref pattern) = search.pattern {
+        headers.push_str(&format!("Pattern: {}\r\n", pattern));
+    }
+    if let Some(since) = search.since {
+        headers.push_str(&format!("Since: {}\r\n", ago(since)));
+    }
+    if let Some(until) = search.until {
+        headers.push_str(&format!("Until: {}\r\n", ago(until)));
+    }
+    let input_stream = open(alias, &token, &headers)?;
+
     let out = io::stdout();
     let err = io::stderr();
     let mut out = out.lock();
     let mut err = err.lock();
+    let mut pid = None;
+    let mut found = 0;
+    read_until_closed(input_stream, |timestamp, frame| {
+        match frame {
+            Frame::Started(started) => pid = Some(started),
+            Frame::Stdout(_) | Frame::Stderr(_) => found += 1,
+            _ => {}
+        }
+        match format {
+            Format::Text => frame.write_to(&mut out, &mut err),
+            Format::Json => print_json(alias, timestamp, pid, frame),
+        }
+    })?;
+    out.flush()?;
+    Ok(found)
+}
+
+// Prints the commands run by the running instance, with their listening
+// ports. Returns the number of running commands.
+pub fn run_status(format: Format) -> io::Result<usize> {
+    let running = fetch_statuses()?;
 
+    let now = timestamp_millis(SystemTime::now());
+    for status in running.iter() {
+        match format {
+            Format::Json => println!("{}", serde_json::to_string(status)?),
+            Format::Text => {
+                let uptime = Duration::from_millis(now.saturating_sub(status.started_at));
+                let mut line = format!(
+                    "{}\tPID {}\tup {}",
+                    status.alias,
+                    status.pid,
+                    format_age(uptime)
+                );
+                if let Some(ref stats) = status.stats {
+                    line.push_str(&format!(
+                        "\tCPU {:.1}%\tRSS {}",
+                        stats.cpu_percent,
+                        format_bytes(stats.rss_bytes)
+                    ));
+                    let urls: Vec<_> = stats.ports.iter().map(|port| local_url(*port)).collect();
+                    if !urls.is_empty() {
+                        line.push_str(&format!("\t{}", urls.join(" ")));
+                    }
+                }
+                println!("{}", line);
+            }
+        }
+    }
+    if running.is_empty() && format == Format::Text {
+        println!("No commands running");
+    }
+    Ok(running.len())
+}
+
+// Sends the signal to the process group of a running command.
+pub fn run_signal(alias: &str, signal: i32) -> io::Result<()> {
+    let token = read_token()?;
+    let headers = format!("Request: signal\r\nSignal: {}\r\n", signal);
+    let input_stream = open(alias, &token, &headers)?;
+    read_until_closed(input_stream, |_timestamp, _frame| Ok(()))?;
+    println!("Sent {} to {}", signal_name(signal), alias.trim());
+    Ok(())
+}
+
+// The commands the running instance has started, sorted by alias.
+pub fn fetch_statuses() -> io::Result<Vec<CommandStatus>> {
+    let token = read_token()?;
+    let input_stream = open("", &token, "Request: status\r\n")?;
+    let mut body = vec![];
+    read_until_closed(input_stream, |_timestamp, frame| {
+        if let Frame::Stdout(bytes) = frame {
+            body.extend_from_slice(bytes);
+        }
+        Ok(())
+    })?;
+    serde_json::from_slice(&body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn format_age(age: Duration) -> String {
+    let seconds = age.as_secs();
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+// Accepts ages like `90`, `30s`, `10m`, `2h` or `1d`.
+pub fn parse_age(age: &str) -> Result<Duration, String> {
+    let age = age.trim();
+    let (number, unit) = match age.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => age.split_at(pos),
+        None => (age, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", age))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "Invalid duration unit: {} (expected s, m, h or d)",
+                unit
+            ))
+        }
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+fn stream_logs<R: Read>(
+    input_stream: R,
+    options: ClientOptions,
+    filter: &LogFilter,
+) -> io::Result<ExitReason> {
+    let out = io::stdout();
+    let err = io::stderr();
+    let mut out = out.lock();
+    let mut err = err.lock();
+
+    let exit = read_frames(
+        input_stream,
+        options,
+        filter,
+        |_timestamp, frame| match frame {
+            Frame::Started(pid) if options.follow_restarts => {
+                writeln!(err, "[topspin] started (PID: {})", pid)
+            }
+            Frame::Exited(reason) => {
+                out.flush()?;
+                writeln!(err, "[topspin] {}, waiting for restart...", reason)
+            }
+            frame => frame.write_to(&mut out, &mut err),
+        },
+    );
+    out.flush()?;
+    exit
+}
+
+// Prints whole lines prefixed with the alias, so outputs of several commands
+// can share the terminal.
+fn follow_logs<R: Read>(
+    input_stream: R,
+    prefix: &Prefix,
+    options: ClientOptions,
+    filter: &LogFilter,
+) -> io::Result<ExitReason> {
+    let mut out_lines = LineBuffer::default();
+    let mut err_lines = LineBuffer::default();
+    let print_out = |line: &[u8]| prefix.write_line(&mut io::stdout().lock(), line);
+    let print_err = |line: &[u8]| prefix.write_line(&mut io::stderr().lock(), line);
+
+    // Frames hold whole lines, or partial lines the command did not finish in time.
+    let exit = read_frames(
+        input_stream,
+        options,
+        filter,
+        |_timestamp, frame| match frame {
+            Frame::Stdout(bytes) => {
+                out_lines.push(bytes, print_out)?;
+                out_lines.flush(print_out)
+            }
+            Frame::Stderr(bytes) => {
+                err_lines.push(bytes, print_err)?;
+                err_lines.flush(print_err)
+            }
+            Frame::Started(pid) if options.follow_restarts => {
+                print_err(format!("started (PID: {})", pid).as_bytes())
+            }
+            Frame::Exited(reason) => {
+                out_lines.flush(print_out)?;
+                err_lines.flush(print_err)?;
+                print_err(format!("{}, waiting for restart...", reason).as_bytes())
+            }
+            _ => Ok(()),
+        },
+    );
+    out_lines.flush(print_out)?;
+    err_lines.flush(print_err)?;
+    exit
+}
+
+#[derive(Debug, Serialize)]
+struct JsonLine<'a> {
+    alias: &'a str,
+    stream: &'static str,
+    // Millis since epoch
+    timestamp: Option<u64>,
+    pid: Option<u32>,
+    // Invalid UTF-8 sequences are replaced with U+FFFD.
+    text: Cow<'a, str>,
+}
+
+// Prints a JSON object per line, for `jq` and other tooling.
+fn json_logs<R: Read>(
+    input_stream: R,
+    alias: &str,
+    options: ClientOptions,
+    filter: &LogFilter,
+) -> io::Result<ExitReason> {
+    let mut pid = None;
+    read_frames(input_stream, options, filter, |timestamp, frame| {
+        if let Frame::Started(started) = frame {
+            pid = Some(started);
+        }
+        print_json(alias, timestamp, pid, frame)
+    })
+}
+
+fn print_json(
+    alias: &str,
+    timestamp: Option<u64>,
+    pid: Option<u32>,
+    frame: Frame<'_>,
+) -> io::Result<()> {
+    let (stream, bytes) = match frame {
+        Frame::Stdout(bytes) => ("stdout", bytes),
+        Frame::Stderr(bytes) => ("stderr", bytes),
+        _ => return Ok(()),
+    };
+    let line = JsonLine {
+        alias,
+        stream,
+        timestamp,
+        pid,
+        text: String::from_utf8_lossy(bytes.strip_suffix(b"\n").unwrap_or(bytes)),
+    };
+    let mut buf = serde_json::to_vec(&line)?;
+    buf.push(b'\n');
+    // One write per line, so it does not interleave with other threads.
+    io::stdout().lock().write_all(&buf)
+}
+
+// Decodes frames until the command exits. Output and `Started` frames are
+// handed to `on_frame` along with their timestamp (v2+ only). When following
+// restarts, so are `Exited` frames, and the stream only ends with the
+// connection. Output frames left out by `filter` are skipped.
+fn read_frames<R, F>(
+    mut input_stream: R,
+    options: ClientOptions,
+    filter: &LogFilter,
+    mut on_frame: F,
+) -> io::Result<ExitReason>
+where
+    R: Read,
+    F: FnMut(Option<u64>, Frame<'_>) -> io::Result<()>,
+{
+    let mut filter = FilterState::new(filter);
     let mut buf = Buffer::new();
+    // The server answers the hello with frames of the negotiated version.
+    let mut version = Version::LATEST;
     loop {
-        let buf_full = buf.full();
+        buf.reserve();
         let nread = input_stream.read(buf.available())?;
         buf.advance(nread);
-        if let Some(frame) = buf.read_frame()? {
-            frame.write_to(&mut out, &mut err)?;
+        while let Some((timestamp, frame)) = buf.read_frame(version)? {
+            match frame {
+                Frame::Hello(negotiated) => version = negotiated,
+                Frame::Exited(_) if options.follow_restarts => on_frame(timestamp, frame)?,
+                Frame::Exited(reason) => return Ok(reason),
+                Frame::Error(message) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        String::from_utf8_lossy(message).into_owned(),
+                    ))
+                }
+                frame => filter.push(timestamp, frame, &mut on_frame)?,
+            }
+        }
+        if nread == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed before the command exited",
+            ));
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn frame(self, bytes: &[u8]) -> Frame<'_> {
+        match self {
+            Stream::Stdout => Frame::Stdout(bytes),
+            Stream::Stderr => Frame::Stderr(bytes),
+        }
+    }
+}
+
+struct FilterState<'a> {
+    filter: &'a LogFilter,
+    // Recent lines left out, printed if a matching line follows.
+    before: VecDeque<(Option<u64>, Stream, Vec<u8>)>,
+    // Number of lines still to print after the last matching line.
+    after: usize,
+}
+
+impl<'a> FilterState<'a> {
+    fn new(filter: &'a LogFilter) -> Self {
+        Self {
+            filter,
+            before: VecDeque::with_capacity(filter.context),
+            after: 0,
+        }
+    }
+
+    // Hands the frame to `emit` if it passes the filter, along with the context
+    // lines before it. Frames other than outputs always pass.
+    fn push<F>(&mut self, timestamp: Option<u64>, frame: Frame<'_>, emit: &mut F) -> io::Result<()>
+    where
+        F: FnMut(Option<u64>, Frame<'_>) -> io::Result<()>,
+    {
+        let (stream, bytes) = match frame {
+            Frame::Stdout(bytes) if !self.filter.skip_stdout => (Stream::Stdout, bytes),
+            Frame::Stderr(bytes) if !self.filter.skip_stderr => (Stream::Stderr, bytes),
+            Frame::Stdout(_) | Frame::Stderr(_) => return Ok(()),
+            frame => return emit(timestamp, frame),
+        };
+        let pattern = match self.filter.pattern {
+            Some(ref pattern) => pattern,
+            None => return emit(timestamp, frame),
+        };
+        let line = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+        if pattern.is_match(line) != self.filter.invert {
+            for (timestamp, stream, line) in self.before.drain(..) {
+                emit(timestamp, stream.frame(&line))?;
+            }
+            self.after = self.filter.context;
+            emit(timestamp, frame)
+        } else if self.after > 0 {
+            self.after -= 1;
+            emit(timestamp, frame)
+        } else {
+            if self.filter.context > 0 {
+                if self.before.len() == self.filter.context {
+                    self.before.pop_front();
+                }
+                self.before.push_back((timestamp, stream, bytes.to_vec()));
+            }
+            Ok(())
         }
-        if nread == 0 && !buf_full {
+    }
+}
+
+// Decodes frames until the server closes the connection, for one-off requests.
+fn read_until_closed<R, F>(mut input_stream: R, mut on_frame: F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(Option<u64>, Frame<'_>) -> io::Result<()>,
+{
+    let mut buf = Buffer::new();
+    let mut version = Version::LATEST;
+    loop {
+        buf.reserve();
+        let nread = input_stream.read(buf.available())?;
+        buf.advance(nread);
+        while let Some((timestamp, frame)) = buf.read_frame(version)? {
+            match frame {
+                Frame::Hello(negotiated) => version = negotiated,
+                Frame::Error(message) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        String::from_utf8_lossy(message).into_owned(),
+                    ))
+                }
+                frame => on_frame(timestamp, frame)?,
+            }
+        }
+        if nread == 0 {
             return Ok(());
         }
     }
 }
 
+struct Prefix(String);
+
+impl Prefix {
+    fn new(alias: &str, width: usize, color: Option<u8>) -> Self {
+        let label = format!("[{}]", alias);
+        let prefix = match color {
+            Some(color) => format!("\x1b[{}m{:<w$}\x1b[0m ", color, label, w = width + 2),
+            None => format!("{:<w$} ", label, w = width + 2),
+        };
+        Prefix(prefix)
+    }
+
+    // Writes the line in one go, so it does not interleave with other threads.
+    fn write_line<W: Write, L: AsRef<[u8]>>(&self, out: &mut W, line: L) -> io::Result<()> {
+        let line = line.as_ref();
+        let mut buf = Vec::with_capacity(self.0.len() + line.len() + 1);
+        buf.extend_from_slice(self.0.as_bytes());
+        buf.extend_from_slice(line);
+        buf.push(b'\n');
+        out.write_all(&buf)
+    }
+}
+
+// Reassembles lines split across frames.
+#[derive(Debug, Default)]
+struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    // Calls `emit` with every completed line (without the line break).
+    fn push<F>(&mut self, mut bytes: &[u8], mut emit: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        while let Some(pos) = bytes.iter().position(|b| *b == b'\n') {
+            if self.pending.is_empty() {
+                emit(&bytes[..pos])?;
+            } else {
+                self.pending.extend_from_slice(&bytes[..pos]);
+                emit(&self.pending)?;
+                self.pending.clear();
+            }
+            bytes = &bytes[pos + 1..];
+        }
+        self.pending.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    // Emits the trailing partial line, if any.
+    fn flush<F>(&mut self, mut emit: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        if !self.pending.is_empty() {
+            emit(&self.pending)?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct Buffer {
     inner: Box<[u8]>,
@@ -41,6 +648,8 @@ struct Buffer {
 
 impl Buffer {
     const DEFAULT_SIZE: usize = 4096;
+    // Large enough for any frame, whose payload length is a u16.
+    const MAX_SIZE: usize = 128 * 1024;
 
     fn new() -> Self {
         Self {
@@ -75,6 +684,20 @@ impl Buffer {
         }
     }
 
+    // Makes room for more bytes, growing the buffer if it is full of a
+    // partial frame.
+    fn reserve(&mut self) {
+        self.advance(0);
+        if !self.full() {
+            return;
+        }
+        let size = self.inner.len() * 2;
+        assert!(size <= Self::MAX_SIZE);
+        let mut inner = vec![0; size].into_boxed_slice();
+        inner[..self.write_cursor].copy_from_slice(&self.inner[..self.write_cursor]);
+        self.inner = inner;
+    }
+
     fn full(&self) -> bool {
         self.write_cursor == self.inner.len()
     }
@@ -83,15 +706,24 @@ impl Buffer {
         &mut self.inner[self.write_cursor..]
     }
 
-    fn read_frame<'a>(&'a mut self) -> io::Result<Option<Frame<'a>>> {
+    // Frames are prefixed with a timestamp (millis since epoch) from v2 onwards.
+    fn read_frame<'a>(
+        &'a mut self,
+        version: Version,
+    ) -> io::Result<Option<(Option<u64>, Frame<'a>)>> {
         let r = self.read_cursor;
         let w = self.write_cursor;
         let bytes = &self.inner[r..w];
-        let (frame, len) = match Frame::parse(bytes).map_err::<io::Error, _>(Into::into)? {
+        let parsed = match version {
+            Version::V1 => Frame::parse(bytes).map(|p| p.map(|(frame, len)| ((None, frame), len))),
+            Version::V2 => Frame::parse_v2(bytes)
+                .map(|p| p.map(|((timestamp, frame), len)| ((Some(timestamp), frame), len))),
+        };
+        let (frame, len) = match parsed.map_err::<io::Error, _>(Into::into)? {
             Some(x) => x,
             None => return Ok(None),
         };
-        assert!(len <= 1024);
+        assert!(len <= u16::MAX as usize + V2_HEADER_LEN);
         if r + len == w {
             self.read_cursor = 0;
             self.write_cursor = 0;
@@ -102,14 +734,48 @@ impl Buffer {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 enum Frame<'a> {
     Stdout(&'a [u8]),
     Stderr(&'a [u8]),
+    Error(&'a [u8]),
+    Hello(Version),
+    // PID from OS
+    Started(u32),
+    Exited(ExitReason),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ExitReason {
+    Code(i32),
+    Signal(i32),
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::Code(code) => write!(f, "exited with code {}", code),
+            ExitReason::Signal(signal) => write!(f, "killed by signal {}", signal),
+        }
+    }
+}
+
+impl ExitReason {
+    // Mirrors the shell convention for processes killed by a signal.
+    fn exit_code(self) -> i32 {
+        match self {
+            ExitReason::Code(code) => code,
+            ExitReason::Signal(signal) => 128 + signal,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 struct FrameError;
 
+// Parsed value and the number of bytes consumed.
+type Parsed<T> = Result<Option<(T, usize)>, FrameError>;
+
 impl Into<io::Error> for FrameError {
     fn into(self) -> io::Error {
         io::Error::new(io::ErrorKind::Other, "Invalid Frame Tag")
@@ -117,10 +783,10 @@ impl Into<io::Error> for FrameError {
 }
 
 impl<'a> Frame<'a> {
-    const PREFIX_SIZE: usize = size_of::<u8>() + size_of::<u16>();
+    const PREFIX_SIZE: usize = V1_HEADER_LEN;
 
     // (Frame, bytes len consumed)
-    fn parse<'b>(bytes: &'b [u8]) -> Result<Option<(Frame<'b>, usize)>, FrameError> {
+    fn parse<'b>(bytes: &'b [u8]) -> Parsed<Frame<'b>> {
         if bytes.len() < Self::PREFIX_SIZE {
             return Ok(None);
         }
@@ -132,19 +798,55 @@ impl<'a> Frame<'a> {
         if data_end > bytes.len() {
             return Ok(None);
         }
-        let frame = match tag {
-            STDOUT_TAG => Frame::Stdout(&bytes[data_start..data_end]),
-            STDERR_TAG => Frame::Stderr(&bytes[data_start..data_end]),
-            _ => return Err(FrameError),
-        };
+        let frame = Frame::from_tag(tag, &bytes[data_start..data_end])?;
 
         Ok(Some((frame, data_end)))
     }
 
+    // ((timestamp, Frame), bytes len consumed)
+    fn parse_v2<'b>(bytes: &'b [u8]) -> Parsed<(u64, Frame<'b>)> {
+        if bytes.len() < V2_HEADER_LEN {
+            return Ok(None);
+        }
+        let tag: u8 = bytes[0];
+        let timestamp: u64 = u64::from_le_bytes((&bytes[1..9]).try_into().unwrap());
+        let len: u16 = u16::from_le_bytes((&bytes[9..V2_HEADER_LEN]).try_into().unwrap());
+
+        let data_start = V2_HEADER_LEN;
+        let data_end = data_start + (len as usize);
+        if data_end > bytes.len() {
+            return Ok(None);
+        }
+        let frame = Frame::from_tag(tag, &bytes[data_start..data_end])?;
+
+        Ok(Some(((timestamp, frame), data_end)))
+    }
+
+    fn from_tag(tag: u8, payload: &'a [u8]) -> Result<Frame<'a>, FrameError> {
+        let frame = match (tag, payload.len()) {
+            (STDOUT_TAG, _) => Frame::Stdout(payload),
+            (STDERR_TAG, _) => Frame::Stderr(payload),
+            (ERROR_TAG, _) => Frame::Error(payload),
+            (HELLO_TAG, 1) => Frame::Hello(Version::from_u8(payload[0]).ok_or(FrameError)?),
+            (STARTED_TAG, 4) => Frame::Started(u32::from_le_bytes(payload.try_into().unwrap())),
+            (EXITED_TAG, 5) => {
+                let value = i32::from_le_bytes((&payload[1..]).try_into().unwrap());
+                match payload[0] {
+                    EXIT_CODE => Frame::Exited(ExitReason::Code(value)),
+                    EXIT_SIGNAL => Frame::Exited(ExitReason::Signal(value)),
+                    _ => return Err(FrameError),
+                }
+            }
+            _ => return Err(FrameError),
+        };
+        Ok(frame)
+    }
+
     fn write_to(self, out: &mut StdoutLock, err: &mut StderrLock) -> io::Result<()> {
         match self {
             Frame::Stdout(bytes) => out.write_all(bytes),
             Frame::Stderr(bytes) => err.write_all(bytes),
+            Frame::Error(_) | Frame::Hello(_) | Frame::Started(_) | Frame::Exited(_) => Ok(()),
         }
     }
 }
@@ -153,6 +855,7 @@ impl<'a> Frame<'a> {
 mod tests {
     use super::*;
     use quickcheck::{Arbitrary, Gen};
+    use std::mem::size_of;
 
     const FRAME_MAX: usize = 16;
 
@@ -203,6 +906,174 @@ mod tests {
         assert!(passed);
     }
 
+    #[test]
+    fn test_line_buffer() {
+        let mut lines = LineBuffer::default();
+        let mut emitted: Vec<Vec<u8>> = vec![];
+        let mut emit = |line: &[u8]| {
+            emitted.push(line.to_vec());
+            Ok(())
+        };
+        lines.push(b"first li", &mut emit).unwrap();
+        lines.push(b"ne\nsecond\n\nthi", &mut emit).unwrap();
+        lines.push(b"rd", &mut emit).unwrap();
+        lines.flush(&mut emit).unwrap();
+        lines.flush(&mut emit).unwrap();
+
+        assert_eq!(
+            emitted,
+            vec![
+                b"first line".to_vec(),
+                b"second".to_vec(),
+                b"".to_vec(),
+                b"third".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_v2_frames() {
+        use crate::protocol::Event;
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let at = UNIX_EPOCH + Duration::from_millis(1234);
+        let mut bytes = vec![];
+        Event::Hello(Version::V2).encode(Version::V2, at, &mut bytes);
+        Event::Started(42).encode(Version::V2, at, &mut bytes);
+        Event::Stdout(b"out").encode(Version::V2, at, &mut bytes);
+        Event::Exited(ExitStatus::from_raw(3 << 8)).encode(Version::V2, at, &mut bytes);
+        Event::Exited(ExitStatus::from_raw(9)).encode(Version::V2, at, &mut bytes);
+
+        let mut buffer = Buffer::new();
+        buffer.available()[..bytes.len()].copy_from_slice(&bytes);
+        buffer.advance(bytes.len());
+
+        let expected = vec![
+            Frame::Hello(Version::V2),
+            Frame::Started(42),
+            Frame::Stdout(b"out"),
+            Frame::Exited(ExitReason::Code(3)),
+            Frame::Exited(ExitReason::Signal(9)),
+        ];
+        for frame in expected {
+            assert_eq!(
+                buffer.read_frame(Version::V2).unwrap(),
+                Some((Some(1234), frame))
+            );
+        }
+        assert_eq!(buffer.read_frame(Version::V2).unwrap(), None);
+    }
+
+    #[test]
+    fn test_filter() {
+        let run = |filter: &LogFilter| {
+            let mut state = FilterState::new(filter);
+            let mut printed: Vec<String> = vec![];
+            let mut emit = |_: Option<u64>, frame: Frame<'_>| {
+                printed.push(match frame {
+                    Frame::Stdout(bytes) => format!("out:{}", String::from_utf8_lossy(bytes)),
+                    Frame::Stderr(bytes) => format!("err:{}", String::from_utf8_lossy(bytes)),
+                    frame => format!("{:?}", frame),
+                });
+                Ok(())
+            };
+            let frames = [
+                Frame::Stdout(b"a\n"),
+                Frame::Stderr(b"b\n"),
+                Frame::Stdout(b"match\n"),
+                Frame::Stdout(b"c\n"),
+                Frame::Stderr(b"d\n"),
+                Frame::Started(7),
+            ];
+            for frame in frames.iter() {
+                state.push(None, *frame, &mut emit).unwrap();
+            }
+            printed
+        };
+
+        let pattern = Some(Regex::new("^mat").unwrap());
+        let grep = LogFilter {
+            pattern: pattern.clone(),
+            ..LogFilter::default()
+        };
+        assert_eq!(run(&grep), vec!["out:match\n", "Started(7)"]);
+
+        let context = LogFilter {
+            pattern: pattern.clone(),
+            context: 1,
+            ..LogFilter::default()
+        };
+        assert_eq!(
+            run(&context),
+            vec!["err:b\n", "out:match\n", "out:c\n", "Started(7)"]
+        );
+
+        let inverted = LogFilter {
+            pattern,
+            invert: true,
+            skip_stdout: true,
+            ..LogFilter::default()
+        };
+        assert_eq!(run(&inverted), vec!["err:b\n", "err:d\n", "Started(7)"]);
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_age("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_age("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse_age("10 minutes").is_err());
+        assert!(parse_age("m").is_err());
+    }
+
+    #[test]
+    fn test_json_line() {
+        let line = JsonLine {
+            alias: "api",
+            stream: "stderr",
+            timestamp: Some(1234),
+            pid: Some(42),
+            text: String::from_utf8_lossy(b"caf\xc3\xa9 \"ok\" \xff"),
+        };
+        assert_eq!(
+            serde_json::to_string(&line).unwrap(),
+            r#"{"alias":"api","stream":"stderr","timestamp":1234,"pid":42,"text":"café \"ok\" �"}"#
+        );
+        assert_eq!("json".parse(), Ok(Format::Json));
+        assert!("yaml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_buffer_grows() {
+        use crate::protocol::Event;
+        use std::time::SystemTime;
+
+        let output = vec![b'x'; u16::MAX as usize];
+        let mut bytes = vec![];
+        Event::Stdout(&output).encode(Version::V2, SystemTime::now(), &mut bytes);
+        Event::Stderr(b"done").encode(Version::V2, SystemTime::now(), &mut bytes);
+
+        let mut buffer = Buffer::new();
+        let mut input = &bytes[..];
+        let mut frames = vec![];
+        while frames.len() < 2 {
+            buffer.reserve();
+            let nread = input.read(buffer.available()).unwrap();
+            buffer.advance(nread);
+            while let Some((_, frame)) = buffer.read_frame(Version::V2).unwrap() {
+                frames.push(match frame {
+                    Frame::Stdout(bytes) => (STDOUT_TAG, bytes.len()),
+                    Frame::Stderr(bytes) => (STDERR_TAG, bytes.len()),
+                    _ => panic!("unexpected frame"),
+                });
+            }
+        }
+        assert_eq!(frames, vec![(STDOUT_TAG, output.len()), (STDERR_TAG, 4)]);
+    }
+
     fn check_buffer(frames: Vec<FrameMeta>, chunk_sizes: Vec<usize>) -> bool {
         if frames.is_empty() || chunk_sizes.is_empty() {
             return true;
@@ -235,7 +1106,7 @@ mod tests {
                 }
                 let nread = slice.read(buffer.available()).unwrap();
                 buffer.advance(nread);
-                if buffer.read_frame().is_err() {
+                if buffer.read_frame(Version::V1).is_err() {
                     // Should never read illegal tag byte.
                     return false;
                 }
@@ -1,31 +1,334 @@
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{mpsc::Sender, oneshot};
 
 use log;
+use regex::bytes::Regex;
+
+use crate::auth::{generate_token, verify_token};
+use crate::model::{
+    parse_signal, signal_name, CommandStatus, Framing, Overflow, RunRequest, Statuses, Subscription,
+};
+use crate::protocol::{Event, Version};
+use crate::scrollback::{Scrollbacks, SearchQuery, Stream};
+
+const MAX_HEADERS: usize = 32;
+const DEFAULT_SEARCH_LIMIT: usize = 100;
 
 pub async fn run_log_server(
-    sender: Sender<(String, BufReader<TcpStream>)>,
+    sender: Sender<(Subscription, BufReader<TcpStream>)>,
+    scrollbacks: Scrollbacks,
+    statuses: Statuses,
+    requests: Sender<RunRequest>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let token = Arc::new(generate_token()?);
     let mut listener = TcpListener::bind("127.0.0.1:9527").await?;
     loop {
         let (stream, _) = listener.accept().await?;
         let sender_ = sender.clone();
+        let token_ = token.clone();
+        let scrollbacks_ = scrollbacks.clone();
+        let statuses_ = statuses.clone();
+        let requests_ = requests.clone();
         tokio::spawn(async move {
-            let _ = handle_stream(sender_, stream).await;
+            let _ = handle_stream(
+                sender_,
+                requests_,
+                stream,
+                &token_,
+                &scrollbacks_,
+                &statuses_,
+            )
+            .await;
         });
     }
 }
 
+// v1 clients send the token and alias lines. v2+ clients start with a hello line
+// (eg. `TOPSPIN/2`), followed by `Name: value` headers and an empty line. With
+// a `Request: search` header, they get lines from the scrollback rather than
+// live outputs, with `Request: status` the running commands. `Request: signal`
+// sends the `Signal` header to the command.
 async fn handle_stream(
-    mut sender: Sender<(String, BufReader<TcpStream>)>,
+    mut sender: Sender<(Subscription, BufReader<TcpStream>)>,
+    requests: Sender<RunRequest>,
     stream: TcpStream,
+    token: &str,
+    scrollbacks: &Scrollbacks,
+    statuses: &Statuses,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut stream = BufReader::new(stream);
     let mut line = String::new();
     stream.read_line(&mut line).await?;
-    log::info!("Connecting to: {}", &line);
-    sender.send((line, stream)).await?;
+
+    let subscription = if let Some(version) = Version::negotiate(&line) {
+        let mut headers = read_headers(&mut stream).await?;
+        let authenticated = headers
+            .get("token")
+            .filter(|presented| verify_token(token, presented))
+            .is_some();
+        if !authenticated {
+            log::warn!("Rejecting unauthenticated log connection");
+            return reject(&mut stream, version, "Authentication failed: invalid token").await;
+        }
+        if headers
+            .get("request")
+            .filter(|r| r.as_str() == "status")
+            .is_some()
+        {
+            return status(&mut stream, version, statuses).await;
+        }
+        let alias = match headers.remove("alias") {
+            Some(alias) => alias,
+            None => return reject(&mut stream, version, "Missing alias header").await,
+        };
+        if headers
+            .get("request")
+            .filter(|r| r.as_str() == "search")
+            .is_some()
+        {
+            return search(&mut stream, version, alias.trim(), &headers, scrollbacks).await;
+        }
+        if headers
+            .get("request")
+            .filter(|r| r.as_str() == "signal")
+            .is_some()
+        {
+            return signal(
+                &mut stream,
+                version,
+                alias.trim(),
+                &headers,
+                statuses,
+                requests,
+            )
+            .await;
+        }
+        write_event(&mut stream, version, Event::Hello(version)).await?;
+        Subscription {
+            alias,
+            version,
+            wait: is_set(headers.get("wait")),
+            follow_restarts: is_set(headers.get("follow-restarts")),
+            overflow: parse_overflow(headers.get("overflow")),
+            framing: parse_framing(headers.get("framing")),
+        }
+    } else {
+        if !verify_token(token, &line) {
+            log::warn!("Rejecting unauthenticated log connection");
+            return reject(
+                &mut stream,
+                Version::V1,
+                "Authentication failed: invalid token",
+            )
+            .await;
+        }
+        line.clear();
+        stream.read_line(&mut line).await?;
+        Subscription {
+            alias: line,
+            version: Version::V1,
+            wait: false,
+            follow_restarts: false,
+            overflow: Overflow::Disconnect,
+            framing: Framing::Chunks,
+        }
+    };
+    log::info!(
+        "Connecting to: {} (protocol v{})",
+        subscription.alias.trim(),
+        subscription.version as u8
+    );
+    sender.send((subscription, stream)).await?;
 
     Ok(())
 }
+
+// Header names are case insensitive.
+async fn read_headers(
+    stream: &mut BufReader<TcpStream>,
+) -> tokio::io::Result<HashMap<String, String>> {
+    let mut headers = HashMap::new();
+    let mut line = String::new();
+    for _ in 0..MAX_HEADERS {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some(sep) = line.find(':') {
+            let name = line[..sep].trim().to_ascii_lowercase();
+            let value = line[sep + 1..].trim().to_string();
+            headers.insert(name, value);
+        }
+    }
+    Ok(headers)
+}
+
+fn is_set(flag: Option<&String>) -> bool {
+    match flag.map(|value| value.to_ascii_lowercase()) {
+        Some(value) => value == "true" || value == "1" || value == "yes",
+        None => false,
+    }
+}
+
+// `Overflow: drop` keeps slow clients around at the cost of missing outputs.
+fn parse_overflow(policy: Option<&String>) -> Overflow {
+    match policy.map(|value| value.to_ascii_lowercase()) {
+        Some(ref value) if value == "drop" => Overflow::DropFrames,
+        _ => Overflow::Disconnect,
+    }
+}
+
+// `Framing: lines` asks for whole lines rather than raw chunks of outputs.
+fn parse_framing(framing: Option<&String>) -> Framing {
+    match framing.map(|value| value.to_ascii_lowercase()) {
+        Some(ref value) if value == "lines" => Framing::Lines,
+        _ => Framing::Chunks,
+    }
+}
+
+// Writes the matching lines, each run's preceded by a `Started` frame, then
+// closes the connection.
+async fn search(
+    stream: &mut BufReader<TcpStream>,
+    version: Version,
+    alias: &str,
+    headers: &HashMap<String, String>,
+    scrollbacks: &Scrollbacks,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pattern = match headers.get("pattern").map(|p| Regex::new(p)).transpose() {
+        Ok(pattern) => pattern,
+        Err(err) => return reject(stream, version, &format!("Invalid pattern: {}", err)).await,
+    };
+    let query = SearchQuery {
+        pattern,
+        since: headers.get("since").and_then(|v| v.parse().ok()),
+        until: headers.get("until").and_then(|v| v.parse().ok()),
+        limit: headers
+            .get("limit")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_SEARCH_LIMIT),
+    };
+    let found = scrollbacks
+        .lock()
+        .expect("mutex error")
+        .get(alias)
+        .map(|scrollback| scrollback.search(&query));
+    let lines = match found {
+        Some(lines) => lines,
+        None => {
+            let message = format!("No outputs kept for command {}", alias);
+            return reject(stream, version, &message).await;
+        }
+    };
+
+    let mut out = Event::Hello(version).frame(version);
+    let mut pid = None;
+    for mut line in lines {
+        let at = UNIX_EPOCH + Duration::from_millis(line.timestamp);
+        if pid != Some(line.pid) {
+            pid = Some(line.pid);
+            Event::Started(line.pid).encode(version, at, &mut out);
+        }
+        line.text.push(b'\n');
+        let event = match line.stream {
+            Stream::Stdout => Event::Stdout(&line.text),
+            Stream::Stderr => Event::Stderr(&line.text),
+        };
+        event.encode(version, at, &mut out);
+    }
+    stream.write_all(&out).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Writes the running commands as a JSON list in stdout frames, then closes
+// the connection.
+async fn status(
+    stream: &mut BufReader<TcpStream>,
+    version: Version,
+    statuses: &Statuses,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut running: Vec<CommandStatus> = statuses
+        .lock()
+        .expect("mutex error")
+        .values()
+        .cloned()
+        .collect();
+    running.sort_by(|a, b| a.alias.cmp(&b.alias));
+
+    let mut out = Event::Hello(version).frame(version);
+    let json = serde_json::to_vec(&running)?;
+    Event::Stdout(&json).encode(version, SystemTime::now(), &mut out);
+    stream.write_all(&out).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Hands the signal to the spawner and waits until it is sent. The client gets a
+// hello frame then, or an error frame when the signal cannot be sent or the
+// spawner is gone.
+async fn signal(
+    stream: &mut BufReader<TcpStream>,
+    version: Version,
+    alias: &str,
+    headers: &HashMap<String, String>,
+    statuses: &Statuses,
+    mut requests: Sender<RunRequest>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let signal = match headers.get("signal").map(|s| parse_signal(s)) {
+        Some(Ok(signal)) => signal,
+        Some(Err(err)) => return reject(stream, version, &err).await,
+        None => return reject(stream, version, "Missing signal header").await,
+    };
+    let id = statuses
+        .lock()
+        .expect("mutex error")
+        .get(alias)
+        .map(|status| status.id);
+    let id = match id {
+        Some(id) => id,
+        None => {
+            let message = format!("Command {} is not running", alias);
+            return reject(stream, version, &message).await;
+        }
+    };
+    // The client is only told once the signal was sent.
+    let (reply, result) = oneshot::channel();
+    requests
+        .send(RunRequest::Signal(id, signal, Some(reply)))
+        .await?;
+    match result.await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => {
+            let message = format!("Cannot send {} to {}: {}", signal_name(signal), alias, err);
+            return reject(stream, version, &message).await;
+        }
+        Err(_) => return reject(stream, version, "The command runner is gone").await,
+    }
+    write_event(stream, version, Event::Hello(version)).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn reject(
+    stream: &mut BufReader<TcpStream>,
+    version: Version,
+    message: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_event(stream, version, Event::Error(message)).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn write_event(
+    stream: &mut BufReader<TcpStream>,
+    version: Version,
+    event: Event<'_>,
+) -> tokio::io::Result<()> {
+    stream.write_all(&event.frame(version)).await
+}
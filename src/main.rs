@@ -1,24 +1,42 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc;
 
-use druid::{AppLauncher, ExtEventSink, LocalizedString, WindowDesc};
+use druid::{AppLauncher, ExtEventSink, WindowDesc};
+use regex::bytes::Regex;
 use structopt::StructOpt;
 
+mod auth;
 mod constants;
+mod export;
+mod import;
 mod log_client;
 mod log_server;
 mod model;
 mod persist;
+mod proc_stats;
+mod protocol;
+mod scrollback;
 mod spawner;
 mod ui;
 
-use crate::constants::RUN_RESPONSES;
-use crate::log_client::run_log_client;
+use crate::constants::{CONFIG_CHANGED, CONFIG_ERROR, RUN_RESPONSES};
+use crate::export::{export, ExportFormat};
+use crate::import::run_import;
+use crate::log_client::{
+    fetch_statuses, parse_age, run_log_client, run_search, run_signal, run_status, ClientOptions,
+    Format, LogFilter, SearchOptions,
+};
 use crate::log_server::run_log_server;
-use crate::model::{RunRequest, RunResponse};
-use crate::persist::load_entries;
+use crate::model::{parse_signal, RunRequest, RunResponse, Subscription};
+use crate::persist::{
+    config_path, find_project, load_entries, load_project, watch_entries, CommandEntry, Commands,
+    ConfigError, PROJECT_FILE,
+};
 use crate::spawner::Spawner;
-use crate::ui::{app_data::AppData, ui_builder, Delegate};
+use crate::ui::{app_data::AppData, ui_builder, window_title, Delegate};
 
 #[cfg(test)]
 #[macro_use(quickcheck)]
@@ -26,19 +44,317 @@ extern crate quickcheck_macros;
 
 #[derive(Debug, StructOpt)]
 struct Opt {
-    // If set run in client mode.
+    /// If set run in client mode, streaming outputs of the given command aliases.
+    #[structopt(short, long)]
+    connect: Vec<String>,
+    /// Stream outputs of every running command in the group (client mode).
     #[structopt(short, long)]
-    connect: Option<String>,
+    group: Option<String>,
+    /// Stream outputs of all running commands (client mode).
+    #[structopt(long)]
+    all: bool,
+    /// Wait for commands which are not running yet (client mode).
+    #[structopt(long)]
+    wait: bool,
+    /// Stay attached when commands exit and stream their next runs (client mode).
+    #[structopt(long)]
+    follow_restarts: bool,
+    /// Skip outputs instead of disconnecting when the client falls behind (client mode).
+    #[structopt(long)]
+    lossy: bool,
+    /// Ask for whole lines rather than raw chunks of outputs, so a line is never
+    /// split across writes (client mode).
+    #[structopt(long)]
+    lines: bool,
+    /// Output format of the client: text or json (one object per line).
+    #[structopt(long, default_value = "text")]
+    format: Format,
+    /// Only print output lines matching the regex (client mode).
+    #[structopt(long)]
+    grep: Option<Regex>,
+    /// Print the lines not matching `--grep` instead (client mode).
+    #[structopt(long, requires = "grep")]
+    invert: bool,
+    /// Number of lines to print around each line matching `--grep` (client mode).
+    #[structopt(short = "C", long, default_value = "0")]
+    context: usize,
+    /// Only print stdout outputs (client mode).
+    #[structopt(long, conflicts_with = "stderr-only")]
+    stdout_only: bool,
+    /// Only print stderr outputs (client mode).
+    #[structopt(long)]
+    stderr_only: bool,
+    /// Project directory with a `.topspin.toml`, whose commands are added to the config.
+    /// Defaults to the closest one from the current directory.
+    #[structopt(long, parse(from_os_str))]
+    project: Option<PathBuf>,
+    /// Profile of the commands, overriding some of their fields.
+    #[structopt(long)]
+    profile: Option<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-const WINDOW_TITLE: LocalizedString<AppData> = LocalizedString::new("Top Spin");
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Print recent outputs of a command, kept by the running instance.
+    Logs {
+        alias: String,
+        /// Only print lines matching the regex.
+        #[structopt(long)]
+        search: Option<String>,
+        /// Only print lines from the given time ago, eg. 30s, 10m or 2h.
+        #[structopt(long, parse(try_from_str = parse_age))]
+        since: Option<Duration>,
+        /// Only print lines older than the given time ago.
+        #[structopt(long, parse(try_from_str = parse_age))]
+        until: Option<Duration>,
+        /// Maximum number of lines to print, the latest are kept.
+        #[structopt(long, default_value = "100")]
+        limit: usize,
+    },
+    /// List the running commands with their PID, resource usage and listening
+    /// ports (`--format json` for one object per line).
+    Status,
+    /// Send a signal to the processes of a running command, eg. HUP to reload
+    /// or STOP and CONT to pause and resume it.
+    Signal {
+        alias: String,
+        /// Name or number of the signal.
+        #[structopt(parse(try_from_str = parse_signal))]
+        signal: i32,
+    },
+    /// Add the commands of a Procfile, package.json scripts, Makefile targets or
+    /// docker-compose services to the config, after a preview.
+    Import {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Group of the added commands.
+        #[structopt(long)]
+        group: Option<String>,
+        /// Add the commands without asking.
+        #[structopt(short, long)]
+        yes: bool,
+    },
+    /// Print the commands as a Procfile, a shell script running them or JSON.
+    Export {
+        /// procfile, sh or json
+        #[structopt(long, default_value = "procfile")]
+        format: ExportFormat,
+        /// Only export the commands of the group.
+        #[structopt(long)]
+        group: Option<String>,
+    },
+    /// Manage the config file.
+    Config {
+        #[structopt(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ConfigAction {
+    /// Validate the config file, exiting with a non-zero status if it has errors.
+    Check,
+}
+
+impl Opt {
+    fn client_mode(&self) -> bool {
+        !self.connect.is_empty() || self.group.is_some() || self.all
+    }
+
+    fn project_root(&self) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+        let dir = match self.project {
+            Some(ref dir) => dir.canonicalize()?,
+            None => return Ok(find_project(&std::env::current_dir()?)),
+        };
+        match find_project(&dir) {
+            Some(root) => Ok(Some(root)),
+            None => Err(format!(
+                "No {} found in {} or its parents",
+                PROJECT_FILE,
+                dir.display()
+            )
+            .into()),
+        }
+    }
+
+    // Commands of the config and project files in the selected profile,
+    // project commands whose alias is in use are skipped like in the GUI.
+    fn commands(&self) -> Result<Vec<(String, CommandEntry)>, Box<dyn std::error::Error>> {
+        let project = match self.project_root()? {
+            Some(root) => load_project(&root)?,
+            None => None,
+        };
+        let mut files: Vec<Commands> = load_entries()?.into_iter().chain(project).collect();
+        if let Some(ref profile) = self.profile {
+            self.check_profile(files.iter())?;
+            for file in files.iter_mut() {
+                file.apply_profile(profile);
+            }
+        }
+        let mut resolved = vec![];
+        let mut commands: Vec<(String, CommandEntry)> = vec![];
+        for file in files.iter() {
+            resolved.extend(file.resolve()?);
+        }
+        for (alias, entry) in resolved {
+            if !commands.iter().any(|(used, _)| *used == alias) {
+                commands.push((alias, entry));
+            }
+        }
+        Ok(commands)
+    }
+
+    fn check_profile<'a>(
+        &self,
+        mut files: impl Iterator<Item = &'a Commands>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.profile {
+            Some(ref profile) if !files.any(|file| file.has_profile(profile)) => {
+                Err(format!("Unknown profile {}", profile).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Command aliases to follow, groups and `--all` are resolved with the config
+    // and project files. Without `--wait`, they only pick the running commands.
+    fn client_aliases(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut aliases = self.connect.clone();
+        if self.group.is_none() && !self.all {
+            return Ok(aliases);
+        }
+        let running: Option<Vec<String>> = if self.wait {
+            None
+        } else {
+            Some(
+                fetch_statuses()?
+                    .into_iter()
+                    .map(|status| status.alias)
+                    .collect(),
+            )
+        };
+        let mut matched = false;
+        for (alias, entry) in self.commands()? {
+            let selected = self.all || (self.group.is_some() && entry.group == self.group);
+            if !selected {
+                continue;
+            }
+            matched = true;
+            let is_running = match running {
+                Some(ref running) => running.contains(&alias),
+                None => true,
+            };
+            if is_running && !aliases.contains(&alias) {
+                aliases.push(alias);
+            }
+        }
+        if !matched && aliases.is_empty() {
+            return Err("No matching commands found in config".into());
+        }
+        if aliases.is_empty() {
+            return Err(
+                "None of the matching commands are running, use --wait to wait for them".into(),
+            );
+        }
+        Ok(aliases)
+    }
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
-    if let Some(alias) = opt.connect {
-        run_log_client(&alias)?;
+    if let Some(Command::Config {
+        action: ConfigAction::Check,
+    }) = opt.command
+    {
+        std::process::exit(check_config(opt.project_root()?.as_deref()));
+    }
+    if let Some(Command::Export { format, ref group }) = opt.command {
+        let mut commands = opt.commands()?;
+        if group.is_some() {
+            commands.retain(|(_, entry)| entry.group == *group);
+        }
+        print!("{}", export(&commands, format)?);
         return Ok(());
     }
+    if let Some(Command::Status) = opt.command {
+        run_status(opt.format)?;
+        return Ok(());
+    }
+    if let Some(Command::Signal { ref alias, signal }) = opt.command {
+        run_signal(alias, signal)?;
+        return Ok(());
+    }
+    if let Some(Command::Import {
+        ref file,
+        ref group,
+        yes,
+    }) = opt.command
+    {
+        std::process::exit(run_import(file, group.clone(), yes)?);
+    }
+    if let Some(Command::Logs {
+        ref alias,
+        ref search,
+        since,
+        until,
+        limit,
+    }) = opt.command
+    {
+        let search = SearchOptions {
+            pattern: search.clone(),
+            since,
+            until,
+            limit,
+        };
+        let found = run_search(alias, &search, opt.format)?;
+        // Like grep, fail when nothing matched.
+        std::process::exit(if found > 0 { 0 } else { 1 });
+    }
+    if opt.client_mode() {
+        let options = ClientOptions {
+            wait: opt.wait,
+            follow_restarts: opt.follow_restarts,
+            lossy: opt.lossy,
+            lines: opt.lines,
+            format: opt.format,
+        };
+        let filter = LogFilter {
+            pattern: opt.grep.clone(),
+            invert: opt.invert,
+            skip_stdout: opt.stderr_only,
+            skip_stderr: opt.stdout_only,
+            context: opt.context,
+        };
+        let code = run_log_client(&opt.client_aliases()?, options, &filter)?;
+        std::process::exit(code);
+    }
+
+    // Report config errors before stderr is redirected, and find the project
+    // before the working directory changes.
+    let (persisted, mut config_error) = match load_entries() {
+        Ok(commands) => (commands, None),
+        Err(err) => {
+            eprintln!("Config error: {}", err);
+            (None, Some(err.to_string()))
+        }
+    };
+    let project = match opt.project_root()? {
+        Some(root) => match load_project(&root) {
+            Ok(commands) => commands.map(|commands| (root, commands)),
+            Err(err) => {
+                eprintln!("Config error: {}", err);
+                config_error = config_error.or_else(|| Some(err.to_string()));
+                None
+            }
+        },
+        None => None,
+    };
+    let files = persisted
+        .iter()
+        .chain(project.iter().map(|(_, commands)| commands));
+    opt.check_profile(files)?;
 
     // do not daemonize in debug mode.
     #[cfg(not(debug_assertions))]
@@ -60,29 +376,102 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }?;
 
     let mut rt = Runtime::new()?;
-    rt.block_on(run())
+    rt.block_on(run(persisted, project, opt.profile, config_error))
 }
 
-async fn run() -> Result<(), Box<dyn ::std::error::Error>> {
-    let persisted = load_entries().await.unwrap_or(None);
+// Validates the config file and the project file, if any.
+fn check_config(project: Option<&Path>) -> i32 {
+    let mut checked = vec![];
+    if let Some(config_path) = config_path() {
+        checked.push((config_path.to_owned(), load_entries()));
+    }
+    if let Some(root) = project {
+        checked.push((root.join(PROJECT_FILE), load_project(root)));
+    }
+    report_checks(checked)
+}
+
+// Fails if any of the files has errors, or if none of them exists.
+fn report_checks(checked: Vec<(PathBuf, Result<Option<Commands>, ConfigError>)>) -> i32 {
+    let mut found = false;
+    let mut failed = false;
+    for (path, loaded) in checked {
+        match loaded {
+            Ok(Some(commands)) => match commands.resolve() {
+                Ok(resolved) => {
+                    println!("{}: {} commands, OK", path.display(), resolved.len());
+                    found = true;
+                }
+                Err(err) => {
+                    eprintln!("{}: {}", path.display(), err);
+                    failed = true;
+                }
+            },
+            Ok(None) => println!("{}: No such file", path.display()),
+            Err(err) => {
+                eprintln!("{}", err);
+                failed = true;
+            }
+        }
+    }
+    if found && !failed {
+        0
+    } else {
+        1
+    }
+}
+
+async fn run(
+    persisted: Option<Commands>,
+    project: Option<(PathBuf, Commands)>,
+    profile: Option<String>,
+    config_error: Option<String>,
+) -> Result<(), Box<dyn ::std::error::Error>> {
     let (mut req_tx, req_rx) = mpsc::channel::<RunRequest>(32);
-    let (sink_tx, sink_rx) = mpsc::channel::<(String, _)>(32);
+    let (sink_tx, sink_rx) = mpsc::channel::<(Subscription, _)>(32);
     let (mut spawner, res_rx) = Spawner::new(req_rx, sink_rx);
+    let scrollbacks = spawner.scrollbacks();
+    let statuses = spawner.statuses();
+    let requests = req_tx.clone();
 
     // create the initial app state
-    let initial_state = if let Some(commands) = persisted {
-        AppData::from_commands(commands, req_tx.clone())
+    let mut initial_state = if let Some(commands) = persisted {
+        AppData::from_commands(commands, req_tx.clone(), scrollbacks.clone())
     } else {
-        AppData::new(req_tx.clone())
+        AppData::new(req_tx.clone(), scrollbacks.clone())
     };
+    if let Some((root, commands)) = project {
+        initial_state.set_project(root, &commands);
+    }
+    initial_state.profile = profile;
+    initial_state.config_error = config_error;
+    let project_root = initial_state.project_root.clone();
 
     tokio::task::spawn_blocking(move || {
         let main_window = WindowDesc::new(ui_builder)
-            .title(WINDOW_TITLE)
+            .title(window_title)
             .window_size((800.0, 600.0));
 
         let launcher = AppLauncher::with_window(main_window);
         let event_sink = launcher.get_external_handle();
+        let config_sink = launcher.get_external_handle();
+        tokio::spawn(async move {
+            let reload = |project, loaded: Result<Commands, ConfigError>| {
+                match loaded {
+                    Ok(commands) => {
+                        config_sink.submit_command(CONFIG_CHANGED, (project, commands), None)?
+                    }
+                    Err(err) => {
+                        log::error!("Cannot reload config: {}", err);
+                        config_sink.submit_command(CONFIG_ERROR, err.to_string(), None)?
+                    }
+                }
+                Ok(())
+            };
+            if let Err(err) = watch_entries(project_root, reload).await {
+                log::error!("Config watch error: {}", err);
+            }
+        });
         tokio::spawn(async move {
             event_bridge(res_rx, event_sink)
                 .await
@@ -110,7 +499,7 @@ async fn run() -> Result<(), Box<dyn ::std::error::Error>> {
     });
 
     tokio::spawn(async move {
-        let _ = run_log_server(sink_tx).await;
+        let _ = run_log_server(sink_tx, scrollbacks, statuses, requests).await;
     });
 
     spawner.run().await?;
@@ -128,3 +517,30 @@ async fn event_bridge(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_checks() {
+        let ok = || {
+            let commands = toml_edit::de::from_str("[commands.web]\ncommand = \"npm\"\n");
+            (PathBuf::from(".topspin.toml"), Ok(Some(commands.unwrap())))
+        };
+        let failed = || {
+            let err = std::io::Error::from_raw_os_error(libc::EACCES);
+            let path = PathBuf::from("topspin.toml");
+            (path.clone(), Err(ConfigError::Io(path, err)))
+        };
+        let missing = || (PathBuf::from("topspin.toml"), Ok(None));
+
+        assert_eq!(report_checks(vec![ok()]), 0);
+        assert_eq!(report_checks(vec![missing(), ok()]), 0);
+        // An error is reported whatever the order of the files.
+        assert_eq!(report_checks(vec![failed(), ok()]), 1);
+        assert_eq!(report_checks(vec![ok(), failed()]), 1);
+        assert_eq!(report_checks(vec![missing()]), 1);
+        assert_eq!(report_checks(vec![]), 1);
+    }
+}
@@ -1,10 +1,20 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
 use std::mem;
 use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
 
 use druid::Data;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
 
-#[derive(Debug, Copy, Clone, Ord, Eq, PartialOrd, PartialEq, Hash, Data)]
+use crate::persist::Limits;
+use crate::protocol::Version;
+
+#[derive(
+    Debug, Copy, Clone, Ord, Eq, PartialOrd, PartialEq, Hash, Data, Serialize, Deserialize,
+)]
 pub struct ProgramId(u32);
 
 pub trait ProgramIdGen {
@@ -30,19 +40,102 @@ pub struct RunCommand {
     pub name: String,
     pub args: Vec<String>,
     pub working_dir: Option<String>,
+    pub env: Vec<(String, String)>,
+    pub limits: Limits,
+    pub ports: Vec<u16>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum RunRequest {
     Run(RunCommand),
     Kill(ProgramId),
+    // Sends the signal to the process group of the program. The result is
+    // reported with `RunResponse::Signaled`, and to the reply channel if any.
+    Signal(
+        ProgramId,
+        i32,
+        Option<oneshot::Sender<Result<(), io::Error>>>,
+    ),
+    // Stops the processes listening on the port, for the program which could
+    // not be started. Processes of other programs are killed like with `Kill`.
+    KillPortOwners(ProgramId, u16),
     Stop,
 }
 
+const SIGNALS: [(&str, i32); 11] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("TERM", libc::SIGTERM),
+    ("STOP", libc::SIGSTOP),
+    ("CONT", libc::SIGCONT),
+    ("TSTP", libc::SIGTSTP),
+    ("WINCH", libc::SIGWINCH),
+];
+
+// Accepts names like `HUP`, `SIGusr1` or numbers.
+pub fn parse_signal(signal: &str) -> Result<i32, String> {
+    let signal = signal.trim();
+    if let Ok(number) = signal.parse::<i32>() {
+        return if number > 0 && number < 65 {
+            Ok(number)
+        } else {
+            Err(format!("Invalid signal number {}", number))
+        };
+    }
+    let upper = signal.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNALS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, number)| *number)
+        .ok_or_else(|| format!("Unknown signal {}", signal))
+}
+
+pub fn signal_name(signal: i32) -> String {
+    match SIGNALS.iter().find(|(_, number)| *number == signal) {
+        Some((name, _)) => format!("SIG{}", name),
+        None => format!("signal {}", signal),
+    }
+}
+
+// A log client asking for the outputs of a command.
 #[derive(Debug, Clone)]
+pub struct Subscription {
+    pub alias: String,
+    pub version: Version,
+    // Keep the client around until the command starts.
+    pub wait: bool,
+    // Keep the client attached after the command exits, for its next run.
+    pub follow_restarts: bool,
+    // What to do when the client falls behind.
+    pub overflow: Overflow,
+    pub framing: Framing,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Framing {
+    // Outputs as they are read from the program.
+    Chunks,
+    // One line per frame, partial lines are sent after a short timeout.
+    Lines,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Overflow {
+    // Disconnect the client with an error frame.
+    Disconnect,
+    // Keep the client, dropping outputs until it catches up.
+    DropFrames,
+}
+
+#[derive(Debug)]
 pub enum SpawnerInput<W> {
     RunRequest(RunRequest),
-    Sink(String, W),
+    Sink(Subscription, W),
 }
 
 impl<W> From<RunRequest> for SpawnerInput<W> {
@@ -51,18 +144,78 @@ impl<W> From<RunRequest> for SpawnerInput<W> {
     }
 }
 
-impl<W> From<(String, W)> for SpawnerInput<W> {
-    fn from(x: (String, W)) -> SpawnerInput<W> {
+impl<W> From<(Subscription, W)> for SpawnerInput<W> {
+    fn from(x: (Subscription, W)) -> SpawnerInput<W> {
         SpawnerInput::Sink(x.0, x.1)
     }
 }
 
+// Resource usage of a program along with the processes it started.
+#[derive(Debug, Clone, Default, PartialEq, Data, Serialize, Deserialize)]
+pub struct ProcessStats {
+    pub cpu_percent: f64,
+    pub rss_bytes: u64,
+    pub threads: u32,
+    // Listening TCP ports
+    #[data(same_fn = "PartialEq::eq")]
+    pub ports: Vec<u16>,
+}
+
+// A running command, as reported by `topspin status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandStatus {
+    pub id: ProgramId,
+    pub alias: String,
+    pub pid: u32,
+    // Milliseconds since the epoch
+    pub started_at: u64,
+    // Unset until sampled
+    pub stats: Option<ProcessStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Data)]
+pub struct PortConflict {
+    pub port: u16,
+    // PID and description of the processes listening on the port, empty when
+    // they cannot be told, eg. when run by another user.
+    #[data(same_fn = "PartialEq::eq")]
+    pub owners: Vec<(u32, String)>,
+}
+
+impl fmt::Display for PortConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Port {} is in use", self.port)?;
+        if self.owners.is_empty() {
+            return write!(f, " by another process");
+        }
+        let owners: Vec<String> = self
+            .owners
+            .iter()
+            .map(|(pid, owner)| format!("PID {} ({})", pid, owner))
+            .collect();
+        write!(f, " by {}", owners.join(", "))
+    }
+}
+
+// Running commands by alias.
+pub type Statuses = Arc<Mutex<HashMap<String, CommandStatus>>>;
+
 #[derive(Debug)]
 pub enum RunResponse {
     // (InternalId, PID from OS)
     Started(ProgramId, u32),
     Exited(ProgramId, ExitStatus),
     IoError(ProgramId, io::Error),
+    // Sampled while the program runs.
+    Stats(ProgramId, ProcessStats),
+    // The program was not started, another process listens on one of its
+    // ports.
+    PortInUse(ProgramId, PortConflict),
+    // Whether the signal of a `RunRequest::Signal` was sent.
+    Signaled(ProgramId, i32, Result<(), io::Error>),
+    // Whether the owners of the port of a `RunRequest::KillPortOwners` were
+    // stopped.
+    PortOwnersKilled(ProgramId, Result<(), io::Error>),
 }
 
 impl RunResponse {
@@ -71,6 +224,10 @@ impl RunResponse {
             RunResponse::Started(id, _) => *id,
             RunResponse::Exited(id, _) => *id,
             RunResponse::IoError(id, _) => *id,
+            RunResponse::Stats(id, _) => *id,
+            RunResponse::PortInUse(id, _) => *id,
+            RunResponse::Signaled(id, _, _) => *id,
+            RunResponse::PortOwnersKilled(id, _) => *id,
         }
     }
 }
@@ -144,4 +301,15 @@ mod tests {
         assert_eq!(prev, "A");
         assert_eq!(map.get(key).unwrap(), "B");
     }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("HUP"), Ok(libc::SIGHUP));
+        assert_eq!(parse_signal("sigusr1"), Ok(libc::SIGUSR1));
+        assert_eq!(parse_signal("9"), Ok(libc::SIGKILL));
+        assert!(parse_signal("0").is_err());
+        assert!(parse_signal("NOPE").is_err());
+        assert_eq!(signal_name(libc::SIGSTOP), "SIGSTOP");
+        assert_eq!(signal_name(40), "signal 40");
+    }
 }
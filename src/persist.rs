@@ -1,51 +1,680 @@
-use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use indexmap::IndexMap;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use toml_edit::{value, Array, DocumentMut, InlineTable, Item, Table};
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+// Commands are kept in the order of the config file.
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
 pub struct Commands {
-    commands: BTreeMap<String, CommandEntry>,
+    commands: IndexMap<String, CommandEntry>,
+    // Overrides of command fields, by profile name then command alias.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    profiles: IndexMap<String, IndexMap<String, CommandOverride>>,
+    #[serde(default)]
+    defaults: Defaults,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    vars: IndexMap<String, String>,
+    // Root of the project the commands come from.
+    #[serde(skip)]
+    root: Option<PathBuf>,
 }
 
 impl Commands {
     pub fn into_iter(self) -> impl Iterator<Item = (String, CommandEntry)> {
         self.commands.into_iter()
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &CommandEntry)> {
+        self.commands.iter()
+    }
+
+    pub fn get(&self, alias: &str) -> Option<&CommandEntry> {
+        self.commands.get(alias)
+    }
+
+    pub fn has_profile(&self, profile: &str) -> bool {
+        self.profiles.contains_key(profile)
+    }
+
+    // Overrides of the command, by profile name.
+    pub fn overrides(&self, alias: &str) -> IndexMap<String, CommandOverride> {
+        self.profiles
+            .iter()
+            .filter_map(|(profile, overrides)| {
+                Some((profile.clone(), overrides.get(alias)?.clone()))
+            })
+            .collect()
+    }
+
+    pub fn template(&self) -> Template {
+        Template {
+            defaults: self.defaults.clone(),
+            vars: self.vars.clone(),
+            root: self.root.clone(),
+        }
+    }
+
+    // The commands as they run, see `Template::resolve`.
+    pub fn resolve(&self) -> Result<Vec<(String, CommandEntry)>, String> {
+        let template = self.template();
+        self.commands
+            .iter()
+            .map(|(alias, entry)| match template.resolve(entry) {
+                Ok(entry) => Ok((alias.clone(), entry)),
+                Err(err) => Err(format!("{}: {}", alias, err)),
+            })
+            .collect()
+    }
+
+    // Resolves the commands to their values in the profile.
+    pub fn apply_profile(&mut self, profile: &str) {
+        if let Some(overrides) = self.profiles.get(profile) {
+            for (alias, entry) in self.commands.iter_mut() {
+                if let Some(command_override) = overrides.get(alias) {
+                    command_override.apply(entry);
+                }
+            }
+        }
+    }
+}
+
+// Values of the fields commands leave unset.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Eq, PartialEq)]
+pub struct Defaults {
+    pub working_dir: Option<String>,
+    pub group: Option<String>,
+    // Variables of the commands are added to these.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub env: IndexMap<String, String>,
+}
+
+// What the commands of a file share.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Template {
+    pub defaults: Defaults,
+    // Referenced as `${vars.name}`
+    pub vars: IndexMap<String, String>,
+    // Relative working directories start from the project root.
+    pub root: Option<PathBuf>,
+}
+
+impl Template {
+    // Fills the fields the command leaves unset with the defaults, and
+    // replaces variable references.
+    pub fn resolve(&self, entry: &CommandEntry) -> Result<CommandEntry, String> {
+        let expand = |field: &str, text: &str| {
+            self.expand(text)
+                .map_err(|err| format!("{} in {}", err, field))
+        };
+        let mut working_dir = match entry
+            .working_dir
+            .as_ref()
+            .or(self.defaults.working_dir.as_ref())
+        {
+            Some(dir) => Some(expand("working_dir", dir)?),
+            None => None,
+        };
+        if let Some(ref root) = self.root {
+            let dir = match working_dir {
+                Some(ref dir) => root.join(dir),
+                None => root.clone(),
+            };
+            working_dir = Some(dir.to_string_lossy().into_owned());
+        }
+        let mut env = IndexMap::new();
+        for (key, value) in self.defaults.env.iter().chain(entry.env.iter()) {
+            env.insert(key.clone(), expand(key, value)?);
+        }
+        Ok(CommandEntry {
+            command: expand("command", &entry.command)?,
+            args: match entry.args {
+                Some(ref args) => Some(expand("args", args)?),
+                None => None,
+            },
+            working_dir,
+            group: entry.group.clone().or_else(|| self.defaults.group.clone()),
+            env,
+            shell: entry.shell.clone(),
+            limits: entry.limits,
+            ports: entry.ports.clone(),
+        })
+    }
+
+    // Replaces `${vars.name}` references, other `${...}` are left to the
+    // command.
+    pub fn expand(&self, text: &str) -> Result<String, String> {
+        const PREFIX: &str = "${vars.";
+
+        let mut expanded = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find(PREFIX) {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start + PREFIX.len()..];
+            let end = rest
+                .find('}')
+                .ok_or_else(|| "Unclosed variable reference".to_string())?;
+            let name = &rest[..end];
+            match self.vars.get(name) {
+                Some(value) => expanded.push_str(value),
+                None => return Err(format!("Unknown variable vars.{}", name)),
+            }
+            rest = &rest[end + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+// Fields of a command which a profile can change. Variables are added to the
+// ones of the command.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Eq, PartialEq)]
+pub struct CommandOverride {
+    pub args: Option<String>,
+    pub working_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub env: IndexMap<String, String>,
+}
+
+impl CommandOverride {
+    pub fn apply(&self, entry: &mut CommandEntry) {
+        if self.args.is_some() {
+            entry.args = self.args.clone();
+        }
+        if self.working_dir.is_some() {
+            entry.working_dir = self.working_dir.clone();
+        }
+        for (key, value) in self.env.iter() {
+            entry.env.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct CommandEntry {
     pub command: String,
     pub args: Option<String>,
     pub working_dir: Option<String>,
+    pub group: Option<String>,
+    // Environment variables added to the inherited ones.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub env: IndexMap<String, String>,
+    pub shell: Option<Shell>,
+    #[serde(default, skip_serializing_if = "Limits::is_empty")]
+    pub limits: Limits,
+    // TCP ports the command listens on, checked before it starts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<u16>,
+}
+
+impl CommandEntry {
+    // The program to run and its arguments. In shell mode the command and
+    // arguments make up the command line given to the shell.
+    pub fn program(&self) -> Result<(String, Vec<String>), String> {
+        let args = self.args.as_deref().unwrap_or("");
+        match self.shell.as_ref().and_then(Shell::path) {
+            Some(shell) => {
+                let line = format!("{} {}", self.command, args);
+                Ok((shell, vec!["-c".to_string(), line.trim_end().to_string()]))
+            }
+            None => {
+                let args = shell_words::split(args)
+                    .map_err(|_| "Invalid command arguments".to_string())?;
+                Ok((self.command.clone(), args))
+            }
+        }
+    }
+}
+
+// Resource limits set on the program before it starts, the unset ones are
+// inherited from topspin.
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    // Address space (RLIMIT_AS), in megabytes
+    pub memory_mb: Option<u64>,
+    pub open_files: Option<u64>,
+    pub cpu_seconds: Option<u64>,
+    // Core dump size, in megabytes. 0 turns them off.
+    pub core_mb: Option<u64>,
+    pub nice: Option<i32>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    // The limits which are set, by their key in the config file.
+    pub fn fields(&self) -> Vec<(&'static str, i64)> {
+        let fields = [
+            ("memory_mb", self.memory_mb.map(|v| v as i64)),
+            ("open_files", self.open_files.map(|v| v as i64)),
+            ("cpu_seconds", self.cpu_seconds.map(|v| v as i64)),
+            ("core_mb", self.core_mb.map(|v| v as i64)),
+            ("nice", self.nice.map(i64::from)),
+        ];
+        fields
+            .iter()
+            .filter_map(|(key, field)| Some((*key, (*field)?)))
+            .collect()
+    }
+}
+
+// `shell = true` runs the command line with `$SHELL -c`, or a given shell
+// with `shell = "/bin/bash"`.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum Shell {
+    Enabled(bool),
+    Path(String),
+}
+
+impl Shell {
+    pub fn path(&self) -> Option<String> {
+        match self {
+            Shell::Enabled(true) => {
+                Some(std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string()))
+            }
+            Shell::Enabled(false) => None,
+            Shell::Path(path) => Some(path.clone()),
+        }
+    }
 }
 
+// Per-project config file, in the root directory of a project.
+pub const PROJECT_FILE: &str = ".topspin.toml";
+
+// Root directory of the project opened in the GUI.
+pub type OpenProject = Arc<Mutex<Option<PathBuf>>>;
+
 static CONFIG_PATH: Lazy<Option<std::path::PathBuf>> = Lazy::new(|| get_config_path());
 
-pub async fn load_entries() -> Result<Option<Commands>, Box<dyn std::error::Error>> {
-    let config_path = if let Some(config_path) = CONFIG_PATH.as_ref() {
-        config_path
-    } else {
-        return Ok(None);
-    };
-    let file_contents = tokio::fs::read_to_string(config_path).await?;
-    let commands = toml::from_str(&file_contents)?;
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    // Line and column start from 1.
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+}
 
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            ConfigError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "{}:{}:{}: {}",
+                path.display(),
+                line,
+                column,
+                message.trim_end()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub fn config_path() -> Option<&'static Path> {
+    CONFIG_PATH.as_deref()
+}
+
+// `None` if there is no config file.
+pub fn load_entries() -> Result<Option<Commands>, ConfigError> {
+    match CONFIG_PATH.as_ref() {
+        Some(config_path) => read_entries(config_path),
+        None => Ok(None),
+    }
+}
+
+// The closest directory holding a project file, from `dir` up.
+pub fn find_project(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|dir| dir.join(PROJECT_FILE).is_file())
+        .map(Path::to_owned)
+}
+
+pub fn project_name(root: &Path) -> String {
+    root.file_name()
+        .unwrap_or(root.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Project commands run in the project root, relative working directories
+// start from it, and they are grouped under the project name unless set.
+pub fn load_project(root: &Path) -> Result<Option<Commands>, ConfigError> {
+    let mut commands = match read_entries(&root.join(PROJECT_FILE))? {
+        Some(commands) => commands,
+        None => return Ok(None),
+    };
+    commands.root = Some(root.to_owned());
+    commands
+        .defaults
+        .group
+        .get_or_insert_with(|| project_name(root));
     Ok(Some(commands))
 }
 
-pub fn dump_entries(entries: impl Iterator<Item = (String, CommandEntry)>) -> std::io::Result<()> {
-    if let Some(config_path) = CONFIG_PATH.as_ref() {
-        let commands = Commands {
-            commands: entries.collect(),
-        };
-        let file_contents: String = toml::to_string(&commands).expect("serialize error");
-        std::fs::write(config_path, file_contents)?;
+fn read_entries(path: &Path) -> Result<Option<Commands>, ConfigError> {
+    let file_contents = match std::fs::read_to_string(path) {
+        Ok(file_contents) => file_contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(ConfigError::Io(path.to_owned(), err)),
+    };
+
+    parse_entries(path, &file_contents).map(Some)
+}
+
+fn parse_entries(path: &Path, file_contents: &str) -> Result<Commands, ConfigError> {
+    toml_edit::de::from_str(file_contents).map_err(|err| {
+        let offset = err.span().map(|span| span.start).unwrap_or(0);
+        let before = &file_contents[..offset];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        ConfigError::Parse {
+            path: path.to_owned(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message: err.message().to_string(),
+        }
+    })
+}
+
+// Only the command tables which changed are rewritten, so comments, formatting
+// and the order of commands in the file are kept. A config file with errors is
+// never overwritten.
+pub fn dump_entries(
+    entries: impl Iterator<Item = (String, CommandEntry)>,
+) -> Result<(), ConfigError> {
+    match CONFIG_PATH.as_ref() {
+        Some(config_path) => write_entries(config_path, entries, &[]),
+        None => Ok(()),
+    }
+}
+
+// Saves the commands of a project. Commands in `shadowed` are kept in the
+// file: they are not loaded since the global config has commands with the
+// same aliases.
+pub fn dump_project(
+    root: &Path,
+    entries: impl Iterator<Item = (String, CommandEntry)>,
+    shadowed: &[String],
+) -> Result<(), ConfigError> {
+    write_entries(&root.join(PROJECT_FILE), entries, shadowed)
+}
+
+fn write_entries(
+    path: &Path,
+    entries: impl Iterator<Item = (String, CommandEntry)>,
+    shadowed: &[String],
+) -> Result<(), ConfigError> {
+    let io_error = |err| ConfigError::Io(path.to_owned(), err);
+    let mut document = match std::fs::read_to_string(path) {
+        Ok(file_contents) => {
+            parse_entries(path, &file_contents)?;
+            file_contents.parse().expect("config was parsed")
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => DocumentMut::new(),
+        Err(err) => return Err(io_error(err)),
+    };
+    update_document(&mut document, entries, shadowed);
+    write_atomically(path, &document.to_string()).map_err(io_error)
+}
+
+// Writes to a temporary file renamed over the config, so the config is never
+// left half written. The previous contents are kept as a timestamped backup
+// next to it (eg. `topspin.toml.1600000000000.bak`). A symlinked config is
+// written where it points to, keeping the link and the file's permissions.
+fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    const MAX_BACKUPS: usize = 5;
+
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => path.to_path_buf(),
+        Err(err) => return Err(err),
+    };
+    let path = path.as_path();
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid config path")
+        })?;
+
+    match std::fs::read_to_string(path) {
+        Ok(ref previous) if previous == contents => return Ok(()),
+        Ok(_) => {
+            let millis = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or(0);
+            std::fs::copy(path, dir.join(format!("{}.{}.bak", file_name, millis)))?;
+            prune_backups(dir, file_name, MAX_BACKUPS)?;
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    let tmp_path = dir.join(format!(".{}.tmp", file_name));
+    let mut file = std::fs::File::create(&tmp_path)?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    // Persist the rename itself.
+    std::fs::File::open(dir)?.sync_all()
+}
+
+fn prune_backups(dir: &Path, file_name: &str, keep: usize) -> std::io::Result<()> {
+    let prefix = format!("{}.", file_name);
+    let mut backups: Vec<(u128, std::path::PathBuf)> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let millis = name
+                .strip_prefix(&prefix)?
+                .strip_suffix(".bak")?
+                .parse()
+                .ok()?;
+            Some((millis, entry.path()))
+        })
+        .collect();
+    backups.sort();
+    let stale = backups.len().saturating_sub(keep);
+    for (_, path) in backups.drain(..stale) {
+        std::fs::remove_file(path)?;
     }
     Ok(())
 }
 
+fn update_document(
+    document: &mut DocumentMut,
+    entries: impl Iterator<Item = (String, CommandEntry)>,
+    keep: &[String],
+) {
+    let entries: IndexMap<String, CommandEntry> = entries.collect();
+    if !document.contains_table("commands") {
+        let mut commands = Table::new();
+        commands.set_implicit(true);
+        document.insert("commands", Item::Table(commands));
+    }
+    let commands = document["commands"]
+        .as_table_mut()
+        .expect("commands is a table");
+
+    commands.retain(|alias, _| entries.contains_key(alias) || keep.iter().any(|k| k == alias));
+    for (alias, entry) in entries {
+        if !commands.contains_table(&alias) {
+            commands.insert(&alias, Item::Table(Table::new()));
+        }
+        let table = commands[alias.as_str()]
+            .as_table_mut()
+            .expect("command is a table");
+        let fields = [
+            ("command", Some(entry.command)),
+            ("args", entry.args),
+            ("working_dir", entry.working_dir),
+            ("group", entry.group),
+        ];
+        for (key, field) in fields.iter() {
+            match field {
+                Some(field) => {
+                    if table.get(key).and_then(Item::as_str) != Some(field.as_str()) {
+                        table[key] = value(field.as_str());
+                    }
+                }
+                None => {
+                    table.remove(key);
+                }
+            }
+        }
+        match entry.shell {
+            Some(Shell::Enabled(enabled)) => {
+                if table.get("shell").and_then(Item::as_bool) != Some(enabled) {
+                    table["shell"] = value(enabled);
+                }
+            }
+            Some(Shell::Path(ref path)) => {
+                if table.get("shell").and_then(Item::as_str) != Some(path.as_str()) {
+                    table["shell"] = value(path.as_str());
+                }
+            }
+            None => {
+                table.remove("shell");
+            }
+        }
+
+        let current: Option<IndexMap<&str, &str>> =
+            table.get("env").and_then(Item::as_table_like).map(|env| {
+                env.iter()
+                    .filter_map(|(key, item)| Some((key, item.as_str()?)))
+                    .collect()
+            });
+        let env: IndexMap<&str, &str> = entry
+            .env
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        if env.is_empty() {
+            table.remove("env");
+        } else if current != Some(env) {
+            let mut inline = InlineTable::new();
+            for (key, env_value) in entry.env.iter() {
+                inline.insert(key, env_value.as_str().into());
+            }
+            table["env"] = value(inline);
+        }
+
+        let limits = entry.limits.fields();
+        let current: Option<Vec<(&str, i64)>> =
+            table.get("limits").and_then(Item::as_table_like).map(|t| {
+                t.iter()
+                    .filter_map(|(key, item)| Some((key, item.as_integer()?)))
+                    .collect()
+            });
+        if limits.is_empty() {
+            table.remove("limits");
+        } else if current != Some(limits.clone()) {
+            let mut inline = InlineTable::new();
+            for (key, limit) in limits {
+                inline.insert(key, limit.into());
+            }
+            table["limits"] = value(inline);
+        }
+
+        let current: Option<Vec<i64>> = table
+            .get("ports")
+            .and_then(Item::as_array)
+            .map(|ports| ports.iter().filter_map(|port| port.as_integer()).collect());
+        let ports: Vec<i64> = entry.ports.iter().map(|port| i64::from(*port)).collect();
+        if ports.is_empty() {
+            table.remove("ports");
+        } else if current != Some(ports.clone()) {
+            table["ports"] = value(ports.into_iter().collect::<Array>());
+        }
+    }
+}
+
+// Polls the config file and the file of the open project, and calls
+// `on_change` with the new contents, or the error reading them, whenever one
+// is modified, until `on_change` fails. The project root is `None` for the
+// config file.
+pub async fn watch_entries<F>(
+    open_project: OpenProject,
+    mut on_change: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(
+        Option<PathBuf>,
+        Result<Commands, ConfigError>,
+    ) -> Result<(), Box<dyn std::error::Error>>,
+{
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    let mut last_modified = match CONFIG_PATH.as_ref() {
+        Some(config_path) => modified_at(config_path).await,
+        None => None,
+    };
+    let mut last_project: Option<(PathBuf, Option<SystemTime>)> = None;
+    loop {
+        tokio::time::delay_for(POLL_INTERVAL).await;
+        if let Some(config_path) = CONFIG_PATH.as_ref() {
+            let modified = modified_at(config_path).await;
+            if modified != last_modified {
+                last_modified = modified;
+                // Loading reads the file with blocking calls.
+                let loaded = tokio::task::spawn_blocking(load_entries).await?;
+                if let Some(loaded) = loaded.transpose() {
+                    on_change(None, loaded)?;
+                }
+            }
+        }
+
+        let root = open_project.lock().unwrap().clone();
+        if let Some(root) = root {
+            let modified = modified_at(&root.join(PROJECT_FILE)).await;
+            // A project opened since the last poll was just loaded.
+            let changed = match last_project {
+                Some((ref last_root, last)) => *last_root == root && last != modified,
+                None => false,
+            };
+            last_project = Some((root.clone(), modified));
+            if changed {
+                let loaded = tokio::task::spawn_blocking({
+                    let root = root.clone();
+                    move || load_project(&root)
+                })
+                .await?;
+                if let Some(loaded) = loaded.transpose() {
+                    on_change(Some(root), loaded)?;
+                }
+            }
+        }
+    }
+}
+
+async fn modified_at(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
 fn get_config_path() -> Option<std::path::PathBuf> {
     std::env::var("TOPSPIN_CONFIG")
         .map(Into::into)
@@ -64,7 +693,7 @@ mod tests {
 
     #[test]
     fn test_parse_simple() {
-        let entries: Commands = toml::from_str(
+        let entries: Commands = toml_edit::de::from_str(
             r#"
     [commands.cat]
     command = "cat"
@@ -73,6 +702,7 @@ mod tests {
     command = "nc"
     args ="-l 7000"
     working_dir = "~/"
+    group = "net"
     "#,
         )
         .unwrap();
@@ -87,6 +717,11 @@ mod tests {
                             command: "cat".to_string(),
                             args: None,
                             working_dir: None,
+                            group: None,
+                            env: IndexMap::new(),
+                            shell: None,
+                            limits: Limits::default(),
+                            ports: vec![],
                         }
                     ),
                     (
@@ -95,12 +730,314 @@ mod tests {
                             command: "nc".to_string(),
                             args: Some("-l 7000".to_string()),
                             working_dir: Some("~/".to_string()),
+                            group: Some("net".to_string()),
+                            env: IndexMap::new(),
+                            shell: None,
+                            limits: Limits::default(),
+                            ports: vec![],
                         }
                     )
                 ]
                 .into_iter()
-                .collect()
+                .collect(),
+                profiles: IndexMap::new(),
+                defaults: Defaults::default(),
+                vars: IndexMap::new(),
+                root: None,
             }
         );
     }
+
+    #[test]
+    fn test_apply_profile() {
+        let mut commands: Commands = toml_edit::de::from_str(
+            r#"
+    [commands.api]
+    command = "cargo"
+    args = "run"
+    env = { RUST_LOG = "debug", PORT = "8000" }
+
+    [commands.db]
+    command = "postgres"
+
+    [profiles.staging.api]
+    args = "run -- --backend staging"
+    env = { PORT = "9000" }
+    "#,
+        )
+        .unwrap();
+        assert!(commands.has_profile("staging"));
+        assert_eq!(commands.overrides("api").len(), 1);
+        assert!(commands.overrides("db").is_empty());
+
+        commands.apply_profile("staging");
+        let api = commands.get("api").unwrap();
+        assert_eq!(api.args.as_deref(), Some("run -- --backend staging"));
+        assert_eq!(api.env["RUST_LOG"], "debug");
+        assert_eq!(api.env["PORT"], "9000");
+        assert_eq!(commands.get("db").unwrap().args, None);
+    }
+
+    #[test]
+    fn test_template() {
+        let commands: Commands = toml_edit::de::from_str(
+            r#"
+    [vars]
+    repo = "/src/shop"
+
+    [defaults]
+    working_dir = "${vars.repo}"
+    env = { RUST_LOG = "info", PORT = "80" }
+
+    [commands.web]
+    command = "npm"
+    args = "start -- --api ${API_URL}"
+    working_dir = "${vars.repo}/frontend"
+    env = { PORT = "3000" }
+
+    [commands.api]
+    command = "${vars.repo}/target/api"
+
+    [commands.typo]
+    command = "echo"
+    args = "${vars.rep}"
+    "#,
+        )
+        .unwrap();
+        let template = commands.template();
+
+        let web = template.resolve(commands.get("web").unwrap()).unwrap();
+        assert_eq!(web.working_dir.as_deref(), Some("/src/shop/frontend"));
+        assert_eq!(web.args.as_deref(), Some("start -- --api ${API_URL}"));
+        let env: Vec<_> = web
+            .env
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        assert_eq!(env, vec!["RUST_LOG=info", "PORT=3000"]);
+
+        let api = template.resolve(commands.get("api").unwrap()).unwrap();
+        assert_eq!(api.command, "/src/shop/target/api");
+        assert_eq!(api.working_dir.as_deref(), Some("/src/shop"));
+
+        assert_eq!(
+            commands.resolve().unwrap_err(),
+            "typo: Unknown variable vars.rep in args"
+        );
+    }
+
+    #[test]
+    fn test_shell() {
+        let commands: Commands = toml_edit::de::from_str(
+            r#"
+    [commands.web]
+    command = "npm start | tee out.log"
+    shell = "/bin/bash"
+
+    [commands.cat]
+    command = "cat"
+    args = "'a b' c"
+    shell = false
+    "#,
+        )
+        .unwrap();
+
+        let (name, args) = commands.get("web").unwrap().program().unwrap();
+        assert_eq!(name, "/bin/bash");
+        assert_eq!(args, vec!["-c", "npm start | tee out.log"]);
+        let (name, args) = commands.get("cat").unwrap().program().unwrap();
+        assert_eq!(name, "cat");
+        assert_eq!(args, vec!["a b", "c"]);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let path = Path::new("topspin.toml");
+        let err = parse_entries(path, "[commands.cat]\ncommand = \"cat\"\nargs = -l\n");
+        match err {
+            Err(ConfigError::Parse { line, column, .. }) => assert_eq!((line, column), (3, 9)),
+            _ => panic!("expected a parse error"),
+        }
+
+        let err = parse_entries(path, "[commands.cat]\nargs = \"-l\"\n").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("topspin.toml:1:1: missing field `command`"));
+    }
+
+    #[test]
+    fn test_project() {
+        let mut root = std::env::temp_dir();
+        root.push(format!("topspin-project-{}", std::process::id()));
+        let nested = root.join("src/bin");
+        std::fs::create_dir_all(&nested).unwrap();
+        let contents = r#"[commands.web]
+command = "npm"
+working_dir = "frontend"
+
+[commands.api]
+command = "cargo"
+group = "backend"
+
+[commands.db]
+command = "postgres"
+"#;
+        std::fs::write(root.join(PROJECT_FILE), contents).unwrap();
+
+        let found = find_project(&nested);
+        let commands = load_project(&root).unwrap().unwrap();
+        let name = project_name(&root);
+        let root_dir = root.to_string_lossy().into_owned();
+        let resolved = commands.resolve().unwrap();
+        let groups: Vec<_> = resolved.iter().map(|(_, e)| e.group.clone()).collect();
+        let dirs: Vec<_> = resolved
+            .iter()
+            .map(|(_, e)| e.working_dir.clone())
+            .collect();
+
+        // Saving writes back what was loaded, the shadowed `db` is kept.
+        let entries = commands.into_iter().filter(|(alias, _)| alias != "db");
+        let saved = dump_project(&root, entries, &["db".to_string()])
+            .map(|_| std::fs::read_to_string(root.join(PROJECT_FILE)).unwrap());
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(found, Some(root.clone()));
+        assert_eq!(
+            groups,
+            vec![Some(name.clone()), Some("backend".to_string()), Some(name)]
+        );
+        assert_eq!(
+            dirs,
+            vec![
+                Some(root.join("frontend").to_string_lossy().into_owned()),
+                Some(root_dir.clone()),
+                Some(root_dir),
+            ]
+        );
+        assert_eq!(saved.unwrap(), contents);
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("topspin-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("topspin.toml");
+
+        for i in 0..8 {
+            write_atomically(&path, &format!("# version {}\n", i)).unwrap();
+            // Unchanged contents are not backed up again.
+            write_atomically(&path, &format!("# version {}\n", i)).unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let mut backups: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("bak"))
+            .collect();
+        backups.sort();
+        let oldest = std::fs::read_to_string(&backups[0]).unwrap();
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(contents, "# version 7\n");
+        assert_eq!(backups.len(), 5);
+        assert_eq!(oldest, "# version 2\n");
+        // No temporary file left behind.
+        assert_eq!(files, 6);
+    }
+
+    #[test]
+    fn test_write_through_symlink() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("topspin-test-link-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("dotfiles")).unwrap();
+        let target = dir.join("dotfiles").join("topspin.toml");
+        let link = dir.join("topspin.toml");
+        std::fs::write(&target, "# old\n").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600)).unwrap();
+        symlink(&target, &link).unwrap();
+
+        write_atomically(&link, "# new\n").unwrap();
+        let is_link = std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink();
+        let contents = std::fs::read_to_string(&target).unwrap();
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(is_link);
+        assert_eq!(contents, "# new\n");
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_update_keeps_formatting() {
+        let mut document: DocumentMut = r#"# Dev servers
+[commands.web]
+command = "npm" # node 14
+args = "start"
+
+# Old one
+[commands.api]
+command = "cargo"
+
+[commands.db]
+command = "postgres"
+env = { PGPORT = "5433" } # not the default
+"#
+        .parse()
+        .unwrap();
+        let entry = |command: &str, args: Option<&str>| CommandEntry {
+            command: command.to_string(),
+            args: args.map(ToString::to_string),
+            working_dir: None,
+            group: None,
+            env: IndexMap::new(),
+            shell: None,
+            limits: Limits::default(),
+            ports: vec![],
+        };
+        let mut db = entry("postgres", Some("-D data"));
+        db.env.insert("PGPORT".to_string(), "5433".to_string());
+        let mut cat = entry("cat", None);
+        cat.env.insert("LANG".to_string(), "C".to_string());
+        cat.limits.memory_mb = Some(512);
+        cat.limits.nice = Some(10);
+        cat.ports = vec![3000, 3001];
+        let entries = vec![
+            ("web".to_string(), entry("npm", Some("start"))),
+            ("db".to_string(), db),
+            ("cat".to_string(), cat),
+        ];
+        update_document(&mut document, entries.into_iter(), &[]);
+
+        assert_eq!(
+            document.to_string(),
+            r#"# Dev servers
+[commands.web]
+command = "npm" # node 14
+args = "start"
+
+[commands.db]
+command = "postgres"
+env = { PGPORT = "5433" } # not the default
+args = "-D data"
+
+[commands.cat]
+command = "cat"
+env = { LANG = "C" }
+limits = { memory_mb = 512, nice = 10 }
+ports = [3000, 3001]
+"#
+        );
+        let commands: Commands = toml_edit::de::from_str(&document.to_string()).unwrap();
+        let aliases: Vec<_> = commands.iter().map(|(alias, _)| alias.as_str()).collect();
+        assert_eq!(aliases, vec!["web", "db", "cat"]);
+        assert_eq!(commands.get("cat").unwrap().limits.memory_mb, Some(512));
+    }
 }
@@ -0,0 +1,284 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::time::Instant;
+
+use crate::model::ProcessStats;
+
+// TCP socket state of listening sockets in /proc/net/tcp
+const LISTEN: &str = "0A";
+
+// Samples the stats of a program and the processes it started from /proc.
+// CPU usage is measured since the previous sample.
+pub struct Sampler {
+    pid: u32,
+    // Time and CPU ticks of the previous sample
+    last: Option<(Instant, u64)>,
+}
+
+impl Sampler {
+    pub fn new(pid: u32) -> Self {
+        Sampler { pid, last: None }
+    }
+
+    // `None` once the program is gone.
+    pub fn sample(&mut self, processes: &ProcessTable) -> Option<ProcessStats> {
+        let pids = processes.tree(self.pid);
+        let stats: Vec<&Stat> = pids
+            .iter()
+            .filter_map(|pid| processes.stats.get(pid))
+            .collect();
+        if stats.is_empty() {
+            return None;
+        }
+        let ticks: u64 = stats.iter().map(|stat| stat.ticks).sum();
+        let now = Instant::now();
+        let cpu_percent = match self.last {
+            Some((then, last_ticks)) => {
+                let seconds = now.duration_since(then).as_secs_f64();
+                // Ticks of processes which exited since are gone.
+                let used = ticks.saturating_sub(last_ticks) as f64 / clock_ticks() as f64;
+                if seconds > 0.0 {
+                    100.0 * used / seconds
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        };
+        self.last = Some((now, ticks));
+
+        Some(ProcessStats {
+            cpu_percent,
+            rss_bytes: stats.iter().map(|stat| stat.rss_pages).sum::<u64>() * page_size(),
+            threads: stats.iter().map(|stat| stat.threads).sum(),
+            ports: listening_ports(&pids),
+        })
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+struct Stat {
+    ppid: u32,
+    // User and system CPU time
+    ticks: u64,
+    threads: u32,
+    rss_pages: u64,
+}
+
+fn read_stat(pid: u32) -> Option<Stat> {
+    parse_stat(&fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+// See proc(5), fields are counted from the one after the command name, which
+// can hold spaces and parentheses.
+fn parse_stat(contents: &str) -> Option<Stat> {
+    let fields: Vec<&str> = contents
+        .get(contents.rfind(')')? + 1..)?
+        .split_whitespace()
+        .collect();
+    let field = |n: usize| {
+        fields
+            .get(n - 3)
+            .and_then(|field| field.parse::<u64>().ok())
+    };
+    Some(Stat {
+        ppid: field(4)? as u32,
+        ticks: field(14)? + field(15)?,
+        threads: field(20)? as u32,
+        rss_pages: field(24)?,
+    })
+}
+
+// A snapshot of the processes in /proc. Reading it walks all of /proc, so
+// it is shared by everything looked up at the same time.
+pub struct ProcessTable {
+    stats: HashMap<u32, Stat>,
+    children: HashMap<u32, Vec<u32>>,
+}
+
+impl ProcessTable {
+    pub fn read() -> Self {
+        let mut stats = HashMap::new();
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        if let Ok(dir) = fs::read_dir("/proc") {
+            for pid in dir.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok()) {
+                if let Some(stat) = read_stat(pid) {
+                    children.entry(stat.ppid).or_default().push(pid);
+                    stats.insert(pid, stat);
+                }
+            }
+        }
+        ProcessTable { stats, children }
+    }
+
+    // The process and its descendants.
+    pub fn tree(&self, root: u32) -> Vec<u32> {
+        let mut tree = vec![root];
+        let mut i = 0;
+        while i < tree.len() {
+            if let Some(children) = self.children.get(&tree[i]) {
+                tree.extend(children);
+            }
+            i += 1;
+        }
+        tree
+    }
+}
+
+// Ports of the TCP sockets the processes listen on.
+pub fn listening_ports(pids: &[u32]) -> Vec<u16> {
+    let inodes: HashSet<u64> = pids.iter().flat_map(|pid| socket_inodes(*pid)).collect();
+    if inodes.is_empty() {
+        return vec![];
+    }
+    let mut ports: Vec<u16> = listening_sockets()
+        .into_iter()
+        .filter(|(_, inode)| inodes.contains(inode))
+        .map(|(port, _)| port)
+        .collect();
+    ports.sort_unstable();
+    ports.dedup();
+    ports
+}
+
+// Processes listening on the port, `None` when it is free. The list is empty
+// when the sockets belong to processes which cannot be inspected.
+pub fn port_owners(port: u16) -> Option<Vec<u32>> {
+    let inodes: HashSet<u64> = listening_sockets()
+        .into_iter()
+        .filter(|(listening, _)| *listening == port)
+        .map(|(_, inode)| inode)
+        .collect();
+    if inodes.is_empty() {
+        return None;
+    }
+    let pids = fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|pid| {
+            socket_inodes(*pid)
+                .iter()
+                .any(|inode| inodes.contains(inode))
+        })
+        .collect();
+    Some(pids)
+}
+
+// The command line of the process.
+pub fn command_line(pid: u32) -> Option<String> {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let args: Vec<_> = cmdline
+        .split(|byte| *byte == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect();
+    Some(args.join(" ")).filter(|line| !line.is_empty())
+}
+
+fn socket_inodes(pid: u32) -> Vec<u64> {
+    let fds = match fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(fds) => fds,
+        Err(_) => return vec![],
+    };
+    fds.filter_map(|fd| {
+        let link = fs::read_link(fd.ok()?.path()).ok()?;
+        link.to_str()?
+            .strip_prefix("socket:[")?
+            .strip_suffix(']')?
+            .parse()
+            .ok()
+    })
+    .collect()
+}
+
+// Port and inode of the listening TCP sockets.
+fn listening_sockets() -> Vec<(u16, u64)> {
+    ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .flat_map(|contents| parse_listening(&contents))
+        .collect()
+}
+
+fn parse_listening(contents: &str) -> Vec<(u16, u64)> {
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if *fields.get(3)? != LISTEN {
+                return None;
+            }
+            let port = fields.get(1)?.rsplit(':').next()?;
+            let inode = fields.get(9)?.parse().ok()?;
+            Some((u16::from_str_radix(port, 16).ok()?, inode))
+        })
+        .collect()
+}
+
+// Where a service listening on the port is reached from a browser.
+pub fn local_url(port: u16) -> String {
+    format!("http://localhost:{}", port)
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn clock_ticks() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_CLK_TCK) as u64 }
+}
+
+fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let stat = "4242 (my (odd) app) S 4200 4242 4200 0 -1 4194560 1303 0 0 0 \
+                    150 30 0 0 20 0 7 0 123456 1000000 2500 18446744073709551615";
+        assert_eq!(
+            parse_stat(stat),
+            Some(Stat {
+                ppid: 4200,
+                ticks: 180,
+                threads: 7,
+                rss_pages: 2500,
+            })
+        );
+
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 51234 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:0CEA 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 99999 1 0000000000000000 100 0 0 10 0
+   2: 0100007F:A2C4 0100007F:1F90 01 00000000:00000000 00:00000000 00000000  1000        0 51235 1 0000000000000000 20 4 30 10 -1
+";
+        assert_eq!(parse_listening(tcp), vec![(8080, 51234), (3306, 99999)]);
+    }
+
+    #[test]
+    fn test_sample() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let pid = std::process::id();
+
+        assert!(listening_ports(&[pid]).contains(&port));
+        assert_eq!(port_owners(port), Some(vec![pid]));
+        drop(listener);
+        assert_eq!(port_owners(port), None);
+        let stats = Sampler::new(pid).sample(&ProcessTable::read()).unwrap();
+        assert!(stats.threads >= 1);
+        assert!(stats.rss_bytes > 0);
+    }
+}
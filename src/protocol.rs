@@ -0,0 +1,195 @@
+use std::mem::size_of;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::constants::{
+    ERROR_TAG, EXITED_TAG, HELLO_PREFIX, HELLO_TAG, STARTED_TAG, STDERR_TAG, STDOUT_TAG,
+};
+
+// v1: tag (u8) | len (u16)
+pub const V1_HEADER_LEN: usize = size_of::<u8>() + size_of::<u16>();
+// v2: tag (u8) | timestamp in millis since epoch (u64) | len (u16)
+pub const V2_HEADER_LEN: usize = size_of::<u8>() + size_of::<u64>() + size_of::<u16>();
+
+// v1 clients read frames into 1024 bytes buffers.
+pub const V1_MAX_FRAME_LEN: usize = 1024;
+
+// First byte of the `Exited` payload, followed by an i32 code or signal number.
+pub const EXIT_CODE: u8 = 0;
+pub const EXIT_SIGNAL: u8 = 1;
+
+#[derive(Debug, Copy, Clone, Ord, Eq, PartialOrd, PartialEq, Hash)]
+pub enum Version {
+    // Clients that send the token and alias lines without a hello.
+    V1 = 1,
+    V2 = 2,
+}
+
+impl Version {
+    pub const LATEST: Version = Version::V2;
+
+    pub fn from_u8(version: u8) -> Option<Version> {
+        match version {
+            1 => Some(Version::V1),
+            2 => Some(Version::V2),
+            _ => None,
+        }
+    }
+
+    // Parses a hello line (eg. `TOPSPIN/2`) and picks the highest version both
+    // sides support. Returns `None` if the line is not a hello.
+    pub fn negotiate(hello: &str) -> Option<Version> {
+        let requested: u8 = hello.trim().strip_prefix(HELLO_PREFIX)?.parse().ok()?;
+        if requested == 0 {
+            return None;
+        }
+        Version::from_u8(std::cmp::min(requested, Version::LATEST as u8))
+    }
+
+    pub fn hello(self) -> String {
+        format!("{}{}", HELLO_PREFIX, self as u8)
+    }
+
+    pub fn header_len(self) -> usize {
+        match self {
+            Version::V1 => V1_HEADER_LEN,
+            Version::V2 => V2_HEADER_LEN,
+        }
+    }
+
+    // Longer outputs are split across several frames.
+    pub fn max_payload_len(self) -> usize {
+        match self {
+            Version::V1 => V1_MAX_FRAME_LEN - V1_HEADER_LEN,
+            Version::V2 => u16::MAX as usize,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Event<'a> {
+    Hello(Version),
+    Stdout(&'a [u8]),
+    Stderr(&'a [u8]),
+    // PID from OS
+    Started(u32),
+    Exited(ExitStatus),
+    Error(&'a str),
+}
+
+impl<'a> Event<'a> {
+    fn tag(&self) -> u8 {
+        match self {
+            Event::Hello(_) => HELLO_TAG,
+            Event::Stdout(_) => STDOUT_TAG,
+            Event::Stderr(_) => STDERR_TAG,
+            Event::Started(_) => STARTED_TAG,
+            Event::Exited(_) => EXITED_TAG,
+            Event::Error(_) => ERROR_TAG,
+        }
+    }
+
+    // Appends the encoded frame to `out`, or several frames if the payload does
+    // not fit in one. Events v1 has no frame type for are skipped.
+    pub fn encode(&self, version: Version, timestamp: SystemTime, out: &mut Vec<u8>) {
+        let mut payload = [0u8; size_of::<u8>() + size_of::<i32>()];
+        let payload: &[u8] = match *self {
+            Event::Stdout(bytes) | Event::Stderr(bytes) => bytes,
+            Event::Error(message) => message.as_bytes(),
+            Event::Hello(negotiated) => {
+                payload[0] = negotiated as u8;
+                &payload[..1]
+            }
+            _ if version == Version::V1 => return,
+            Event::Started(pid) => {
+                payload[..4].copy_from_slice(&pid.to_le_bytes());
+                &payload[..4]
+            }
+            Event::Exited(status) => {
+                let (kind, value) = match (status.code(), status.signal()) {
+                    (Some(code), _) => (EXIT_CODE, code),
+                    (None, Some(signal)) => (EXIT_SIGNAL, signal),
+                    (None, None) => (EXIT_CODE, -1),
+                };
+                payload[0] = kind;
+                payload[1..].copy_from_slice(&value.to_le_bytes());
+                &payload[..]
+            }
+        };
+        let max_len = version.max_payload_len();
+        let frames = payload.len() / max_len + 1;
+        out.reserve(frames * version.header_len() + payload.len());
+
+        let mut chunks = payload.chunks(max_len);
+        let first = chunks.next().unwrap_or(&[]);
+        for chunk in std::iter::once(first).chain(chunks) {
+            out.push(self.tag());
+            if version == Version::V2 {
+                out.extend_from_slice(&timestamp_millis(timestamp).to_le_bytes());
+            }
+            out.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            out.extend_from_slice(chunk);
+        }
+    }
+
+    pub fn frame(&self, version: Version) -> Vec<u8> {
+        self.frame_at(version, SystemTime::now())
+    }
+
+    pub fn frame_at(&self, version: Version, timestamp: SystemTime) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(version, timestamp, &mut out);
+        out
+    }
+}
+
+pub fn timestamp_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Version::negotiate("TOPSPIN/2\r\n"), Some(Version::V2));
+        assert_eq!(Version::negotiate("TOPSPIN/9"), Some(Version::LATEST));
+        assert_eq!(Version::negotiate("TOPSPIN/1"), Some(Version::V1));
+        assert_eq!(Version::negotiate("TOPSPIN/0"), None);
+        assert_eq!(Version::negotiate("0123abcd"), None);
+        assert_eq!(
+            Version::negotiate(&Version::LATEST.hello()),
+            Some(Version::LATEST)
+        );
+    }
+
+    #[test]
+    fn test_encode_v1_compat() {
+        let mut out = vec![];
+        let now = SystemTime::now();
+        Event::Stdout(b"hi").encode(Version::V1, now, &mut out);
+        Event::Started(42).encode(Version::V1, now, &mut out);
+        assert_eq!(out, vec![STDOUT_TAG, 2, 0, b'h', b'i']);
+    }
+
+    #[test]
+    fn test_encode_split() {
+        let now = SystemTime::now();
+        let bytes = vec![b'x'; 3000];
+        let v1_frames = Event::Stdout(&bytes).frame_at(Version::V1, now);
+        // 1021 + 1021 + 958 bytes of payload
+        assert_eq!(v1_frames.len(), 3 * V1_HEADER_LEN + 3000);
+        assert_eq!(&v1_frames[..V1_HEADER_LEN], &[STDOUT_TAG, 0xfd, 0x03]);
+
+        let v2_frame = Event::Stdout(&bytes).frame_at(Version::V2, now);
+        assert_eq!(v2_frame.len(), V2_HEADER_LEN + 3000);
+        assert_eq!(&v2_frame[9..V2_HEADER_LEN], &[0xb8, 0x0b]);
+
+        let empty = Event::Stdout(&[]).frame_at(Version::V2, now);
+        assert_eq!(empty.len(), V2_HEADER_LEN);
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use regex::bytes::Regex;
+
+// Recent output lines of every command, by alias. Kept across restarts.
+pub type Scrollbacks = Arc<Mutex<HashMap<String, Scrollback>>>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    // Millis since epoch
+    pub timestamp: u64,
+    pub stream: Stream,
+    // PID from OS
+    pub pid: u32,
+    // Without the line break.
+    pub text: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    // Lines matching the pattern, or every line.
+    pub pattern: Option<Regex>,
+    // Time range in millis since epoch, both ends included.
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    // Only the latest matches are returned.
+    pub limit: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Scrollback {
+    lines: VecDeque<LogLine>,
+    // Total length of the kept lines
+    bytes: usize,
+}
+
+impl Scrollback {
+    const MAX_LINES: usize = 10_000;
+    // Lines can be up to 16 KiB, so the line count alone does not bound the
+    // memory used.
+    const MAX_BYTES: usize = 8 * 1024 * 1024;
+
+    pub fn push(&mut self, line: LogLine) {
+        self.bytes += line.text.len();
+        self.lines.push_back(line);
+        while self.lines.len() > Self::MAX_LINES || self.bytes > Self::MAX_BYTES {
+            match self.lines.pop_front() {
+                Some(oldest) => self.bytes -= oldest.text.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Matching lines, oldest first.
+    pub fn search(&self, query: &SearchQuery) -> Vec<LogLine> {
+        let mut found: Vec<LogLine> = self
+            .lines
+            .iter()
+            .rev()
+            .filter(|line| {
+                query
+                    .until
+                    .filter(|until| line.timestamp > *until)
+                    .is_none()
+            })
+            .take_while(|line| {
+                query
+                    .since
+                    .filter(|since| line.timestamp < *since)
+                    .is_none()
+            })
+            .filter(|line| match query.pattern {
+                Some(ref pattern) => pattern.is_match(&line.text),
+                None => true,
+            })
+            .take(query.limit)
+            .cloned()
+            .collect();
+        found.reverse();
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let mut scrollback = Scrollback::default();
+        for i in 0..Scrollback::MAX_LINES as u64 + 10 {
+            scrollback.push(LogLine {
+                timestamp: i,
+                stream: if i % 2 == 0 {
+                    Stream::Stdout
+                } else {
+                    Stream::Stderr
+                },
+                pid: 1,
+                text: format!("line {}", i).into_bytes(),
+            });
+        }
+        let texts = |query: &SearchQuery| -> Vec<String> {
+            scrollback
+                .search(query)
+                .into_iter()
+                .map(|line| String::from_utf8(line.text).unwrap())
+                .collect()
+        };
+
+        let all = SearchQuery {
+            pattern: None,
+            since: None,
+            until: None,
+            limit: usize::MAX,
+        };
+        assert_eq!(scrollback.search(&all).len(), Scrollback::MAX_LINES);
+        assert_eq!(scrollback.search(&all)[0].timestamp, 10);
+
+        let query = SearchQuery {
+            pattern: Some(Regex::new("^line 1[0-9]$").unwrap()),
+            since: Some(12),
+            until: Some(17),
+            limit: 3,
+        };
+        assert_eq!(texts(&query), vec!["line 15", "line 16", "line 17"]);
+
+        let query = SearchQuery { limit: 10, ..query };
+        assert_eq!(texts(&query).len(), 6);
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let mut scrollback = Scrollback::default();
+        let len = 16 * 1024;
+        for i in 0..Scrollback::MAX_BYTES / len + 5 {
+            scrollback.push(LogLine {
+                timestamp: i as u64,
+                stream: Stream::Stdout,
+                pid: 1,
+                text: vec![b'x'; len],
+            });
+        }
+        assert_eq!(scrollback.lines.len(), Scrollback::MAX_BYTES / len);
+        assert_eq!(scrollback.bytes, Scrollback::MAX_BYTES);
+        assert_eq!(scrollback.lines[0].timestamp, 5);
+    }
+}
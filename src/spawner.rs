@@ -1,51 +1,90 @@
 use std::future::Future;
 
 use std::collections::HashMap;
-use std::mem::size_of;
 use std::pin::Pin;
 use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use std::task::{Context, Poll};
 
 use log;
-use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::process::{Child, ChildStderr, ChildStdout};
 use tokio::sync::{
-    mpsc::{self, error::SendError, Receiver, Sender},
+    mpsc::{
+        self,
+        error::{SendError, TrySendError},
+        Receiver, Sender,
+    },
     oneshot,
 };
+use tokio::time::{delay_until, timeout, Instant};
 
-use crate::constants::{STDERR_TAG, STDOUT_TAG};
-use crate::model::{ProgramId, ProgramMap, RunCommand, RunRequest, RunResponse, SpawnerInput};
+use crate::model::{
+    CommandStatus, Framing, Overflow, PortConflict, ProgramId, ProgramMap, RunCommand, RunRequest,
+    RunResponse, SpawnerInput, Statuses, Subscription,
+};
+use crate::persist::Limits;
+use crate::proc_stats::{command_line, port_owners, ProcessTable, Sampler};
+use crate::protocol::{timestamp_millis, Event, Version};
+use crate::scrollback::{LogLine, Scrollbacks, Stream};
 
-type Unclaimed<T> = Arc<Mutex<Vec<T>>>;
+type Unclaimed<T> = Arc<Mutex<SinkQueue<T>>>;
 
-trait Fatal<T, E>: Into<Result<T, E>> {
-    const MESSAGE: &'static str;
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
 
-    fn die_on_err(self) -> T {
-        match self.into() {
-            Ok(val) => val,
-            Err(_) => {
-                log::warn!("{}", Self::MESSAGE);
-                std::process::exit(1);
-            }
+// Sinks waiting to be picked up by the log forwarder of a program. The queue is
+// closed once the program is gone.
+struct SinkQueue<T> {
+    sinks: Vec<T>,
+    closed: bool,
+}
+
+impl<T> SinkQueue<T> {
+    fn new() -> Self {
+        Self {
+            sinks: Vec::new(),
+            closed: false,
         }
     }
 }
 
-impl<T> Fatal<(), SendError<T>> for Result<(), SendError<T>> {
-    const MESSAGE: &'static str = "Receiver (UI) is gone, killing the program";
+// Responses sent once the receiver (UI) is gone, eg. while shutting down, are
+// dropped. The programs are killed along with the spawner.
+trait Dropped {
+    fn log_dropped(self);
+}
+
+impl<T> Dropped for Result<(), SendError<T>> {
+    fn log_dropped(self) {
+        if self.is_err() {
+            log::debug!("Receiver (UI) is gone, dropping the response");
+        }
+    }
 }
 
 pub struct Spawner<W> {
     requests_chan: Receiver<RunRequest>,
-    sinks_chan: Receiver<(String, W)>,
+    sinks_chan: Receiver<(Subscription, W)>,
+    // Sinks handed back by log forwarders of exited programs, to follow restarts.
+    resubscribe_tx: Sender<LogSink>,
+    resubscribe_rx: Receiver<LogSink>,
+    // Commands whose ports were found free, to be started.
+    checked_tx: Sender<RunCommand>,
+    checked_rx: Receiver<RunCommand>,
+    // Commands found listening on a port to be freed, to be killed.
+    port_owners_tx: Sender<ProgramId>,
+    port_owners_rx: Receiver<ProgramId>,
     responses: Sender<RunResponse>,
     spawned: ProgramMap<oneshot::Sender<Kill>>,
-    log_sinks: ProgramMap<Unclaimed<W>>,
+    log_sinks: ProgramMap<Unclaimed<LogSink>>,
+    // Sinks for commands which are not running yet, by alias.
+    pending_sinks: HashMap<String, Vec<LogSink>>,
     alias_to_id: HashMap<String, ProgramId>,
+    scrollbacks: Scrollbacks,
+    statuses: Statuses,
 }
 
 impl<W> Drop for Spawner<W> {
@@ -57,26 +96,49 @@ impl<W> Drop for Spawner<W> {
     }
 }
 
-impl<W: 'static + Send + AsyncWrite + std::marker::Unpin> Spawner<W> {
+impl<W: 'static + Send + AsyncRead + AsyncWrite + std::marker::Unpin> Spawner<W> {
     pub fn new(
         requests: Receiver<RunRequest>,
-        sinks: Receiver<(String, W)>,
+        sinks: Receiver<(Subscription, W)>,
     ) -> (Self, Receiver<RunResponse>) {
         let (tx, rx) = mpsc::channel(32);
+        let (resubscribe_tx, resubscribe_rx) = mpsc::channel(32);
+        let (checked_tx, checked_rx) = mpsc::channel(32);
+        let (port_owners_tx, port_owners_rx) = mpsc::channel(32);
         (
             Self {
                 requests_chan: requests,
                 sinks_chan: sinks,
+                resubscribe_tx,
+                resubscribe_rx,
+                checked_tx,
+                checked_rx,
+                port_owners_tx,
+                port_owners_rx,
                 responses: tx,
                 spawned: ProgramMap::new(),
                 log_sinks: ProgramMap::new(),
+                pending_sinks: HashMap::new(),
                 alias_to_id: HashMap::new(),
+                scrollbacks: Scrollbacks::default(),
+                statuses: Statuses::default(),
             },
             rx,
         )
     }
 
+    // Recent outputs of the commands, for searching.
+    pub fn scrollbacks(&self) -> Scrollbacks {
+        self.scrollbacks.clone()
+    }
+
+    // Running commands, for `topspin status`.
+    pub fn statuses(&self) -> Statuses {
+        self.statuses.clone()
+    }
+
     pub async fn run(&mut self) -> tokio::io::Result<()> {
+        tokio::spawn(sample_stats(self.responses.clone(), self.statuses.clone()));
         loop {
             let input = tokio::select! {
                 input = self.requests_chan.recv() => {
@@ -93,64 +155,322 @@ impl<W: 'static + Send + AsyncWrite + std::marker::Unpin> Spawner<W> {
                         continue
                     }
                 }
+                sink = self.resubscribe_rx.recv() => {
+                    if let Some(sink) = sink {
+                        self.attach_sink(sink);
+                    }
+                    continue
+                }
+                cmd = self.checked_rx.recv() => {
+                    if let Some(cmd) = cmd {
+                        self.start(cmd);
+                    }
+                    continue
+                }
+                id = self.port_owners_rx.recv() => {
+                    if let Some(id) = id {
+                        self.kill(id);
+                    }
+                    continue
+                }
             };
             match input {
                 SpawnerInput::RunRequest(RunRequest::Run(cmd)) => {
-                    let id = cmd.id;
-                    let sink = Arc::new(Mutex::new(Vec::new()));
-                    let alias = cmd.alias.trim().to_string();
-                    let kill_chan = run_command(cmd, self.responses.clone(), sink.clone());
-                    match kill_chan {
-                        Ok(kill_chan) => {
-                            let _ = self.spawned.insert(id, kill_chan);
-                            let _ = self.alias_to_id.insert(alias, id);
-                            let _ = self.log_sinks.insert(id, sink);
-                        }
-                        Err(err) => {
-                            tokio::spawn({
-                                let mut resp = self.responses.clone();
-                                async move {
-                                    resp.send(RunResponse::IoError(id, err)).await.die_on_err();
-                                }
-                            });
-                        }
+                    if cmd.ports.is_empty() {
+                        self.start(cmd);
+                    } else {
+                        self.check_ports(cmd);
                     }
                 }
-                SpawnerInput::RunRequest(RunRequest::Kill(id)) => {
-                    if let Some(tx) = self.spawned.remove(id) {
-                        let _ = tx.send(Kill);
+                SpawnerInput::RunRequest(RunRequest::Kill(id)) => self.kill(id),
+                SpawnerInput::RunRequest(RunRequest::KillPortOwners(id, port)) => {
+                    self.kill_port_owners(id, port)
+                }
+                SpawnerInput::RunRequest(RunRequest::Signal(id, signal, reply)) => {
+                    let pid = self
+                        .statuses
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .find(|status| status.id == id)
+                        .map(|status| status.pid);
+                    let result = match pid {
+                        Some(pid) => signal_group(pid, signal),
+                        None => Err(std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            "The command is not running",
+                        )),
+                    };
+                    if let Some(reply) = reply {
+                        let copy = match result {
+                            Ok(()) => Ok(()),
+                            Err(ref err) => Err(std::io::Error::new(err.kind(), err.to_string())),
+                        };
+                        let _ = reply.send(copy);
                     }
+                    tokio::spawn({
+                        let mut resp = self.responses.clone();
+                        async move {
+                            resp.send(RunResponse::Signaled(id, signal, result))
+                                .await
+                                .log_dropped();
+                        }
+                    });
                 }
                 SpawnerInput::RunRequest(RunRequest::Stop) => break,
-                SpawnerInput::Sink(alias, sink) => {
-                    if let Some(program_id) = self.alias_to_id.get(alias.trim()) {
-                        if let Some(sinks) = self.log_sinks.get(*program_id) {
+                SpawnerInput::Sink(subscription, writer) => {
+                    self.attach_sink(LogSink::spawn(subscription, writer))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn start(&mut self, cmd: RunCommand) {
+        let id = cmd.id;
+        let sink = Arc::new(Mutex::new(SinkQueue::new()));
+        let alias = cmd.alias.trim().to_string();
+        self.scrollbacks
+            .lock()
+            .unwrap()
+            .entry(alias.clone())
+            .or_default();
+        let kill_chan = run_command(
+            cmd,
+            self.responses.clone(),
+            sink.clone(),
+            self.resubscribe_tx.clone(),
+            self.scrollbacks.clone(),
+            self.statuses.clone(),
+        );
+        match kill_chan {
+            Ok(kill_chan) => {
+                if let Some(pending) = self.pending_sinks.remove(&alias) {
+                    log::info!("Command {} started, streaming logs...", &alias);
+                    let pending = pending.into_iter().filter(|sink| !sink.is_closed());
+                    sink.lock().unwrap().sinks.extend(pending);
+                }
+                let _ = self.spawned.insert(id, kill_chan);
+                let _ = self.alias_to_id.insert(alias, id);
+                let _ = self.log_sinks.insert(id, sink);
+            }
+            Err(err) => {
+                tokio::spawn({
+                    let mut resp = self.responses.clone();
+                    async move {
+                        resp.send(RunResponse::IoError(id, err)).await.log_dropped();
+                    }
+                });
+            }
+        }
+    }
+
+    // Starts the command once no other process listens on its ports. Looking
+    // for the owners walks /proc, so it is done off the runtime.
+    fn check_ports(&self, cmd: RunCommand) {
+        let running: Vec<(String, u32)> = self
+            .statuses
+            .lock()
+            .unwrap()
+            .values()
+            .map(|status| (status.alias.clone(), status.pid))
+            .collect();
+        let mut checked = self.checked_tx.clone();
+        let mut resp = self.responses.clone();
+        tokio::spawn(async move {
+            let ports = cmd.ports.clone();
+            let conflict =
+                tokio::task::spawn_blocking(move || port_conflict(&ports, &running)).await;
+            match conflict {
+                Ok(None) => {
+                    let _ = checked.send(cmd).await;
+                }
+                Ok(Some(conflict)) => {
+                    resp.send(RunResponse::PortInUse(cmd.id, conflict))
+                        .await
+                        .log_dropped();
+                }
+                Err(err) => {
+                    resp.send(RunResponse::IoError(cmd.id, err.into()))
+                        .await
+                        .log_dropped();
+                }
+            }
+        });
+    }
+
+    fn kill(&mut self, id: ProgramId) {
+        if let Some(tx) = self.spawned.remove(id) {
+            let _ = tx.send(Kill);
+        }
+    }
+
+    // Stops the processes listening on the port, off the runtime like the
+    // check of the ports. Commands holding the port are killed by the loop.
+    fn kill_port_owners(&self, id: ProgramId, port: u16) {
+        let running: Vec<(ProgramId, u32)> = self
+            .statuses
+            .lock()
+            .unwrap()
+            .values()
+            .map(|status| (status.id, status.pid))
+            .collect();
+        let mut commands_tx = self.port_owners_tx.clone();
+        let mut resp = self.responses.clone();
+        tokio::spawn(async move {
+            let stopped =
+                tokio::task::spawn_blocking(move || stop_port_owners(port, &running)).await;
+            let result = match stopped {
+                Ok((commands, result)) => {
+                    for command in commands {
+                        let _ = commands_tx.send(command).await;
+                    }
+                    result
+                }
+                Err(err) => Err(err.into()),
+            };
+            resp.send(RunResponse::PortOwnersKilled(id, result))
+                .await
+                .log_dropped();
+        });
+    }
+
+    fn attach_sink(&mut self, sink: LogSink) {
+        let alias = sink.subscription.alias.trim().to_string();
+        let sink = match self.alias_to_id.get(&alias).copied() {
+            Some(program_id) => {
+                let sink = match self.log_sinks.get(program_id) {
+                    Some(queue) => {
+                        let mut queue = queue.lock().unwrap();
+                        if queue.closed {
+                            Some(sink)
+                        } else {
                             log::info!("Command {} is running, streaming logs...", &alias);
-                            let mut sinks = sinks.lock().unwrap();
-                            sinks.push(sink);
-                            drop(sinks);
+                            queue.sinks.push(sink);
+                            None
                         }
                     }
+                    None => Some(sink),
+                };
+                if sink.is_some() {
+                    // The program has exited since.
+                    let _ = self.log_sinks.remove(program_id);
+                    let _ = self.alias_to_id.remove(&alias);
                 }
+                sink
             }
+            None => Some(sink),
+        };
+        let mut sink = match sink {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        if sink.subscription.wait || sink.subscription.follow_restarts {
+            log::info!(
+                "Command {} is not running, waiting for it to start...",
+                &alias
+            );
+            let pending = self.pending_sinks.entry(alias).or_default();
+            // Clients may hang up before the command starts.
+            pending.retain(|sink| !sink.is_closed());
+            pending.push(sink);
+        } else {
+            let message = format!("Command {} is not running", alias);
+            sink.offer(&Event::Error(&message).frame(sink.version()));
         }
+    }
+}
 
-        Ok(())
+// The first of the ports another process listens on, with its owners. Processes
+// of the running commands, given by alias and PID, are named after them.
+fn port_conflict(ports: &[u16], running: &[(String, u32)]) -> Option<PortConflict> {
+    ports.iter().find_map(|port| {
+        let owners = port_owners(*port)?;
+        let processes = ProcessTable::read();
+        let trees: Vec<(&String, Vec<u32>)> = running
+            .iter()
+            .map(|(alias, pid)| (alias, processes.tree(*pid)))
+            .collect();
+        let owners = owners
+            .into_iter()
+            .map(|pid| {
+                let command = trees.iter().find(|(_, tree)| tree.contains(&pid));
+                let owner = match command {
+                    Some((alias, _)) => format!("command {}", alias),
+                    None => command_line(pid).unwrap_or_else(|| "unknown".to_string()),
+                };
+                (pid, owner)
+            })
+            .collect();
+        Some(PortConflict {
+            port: *port,
+            owners,
+        })
+    })
+}
+
+// Sends SIGTERM to the processes listening on the port, as they are now. The
+// running commands among them, given by id and PID, are returned instead.
+fn stop_port_owners(
+    port: u16,
+    running: &[(ProgramId, u32)],
+) -> (Vec<ProgramId>, std::io::Result<()>) {
+    let owners = match port_owners(port) {
+        Some(owners) => owners,
+        None => return (vec![], Ok(())),
+    };
+    let processes = ProcessTable::read();
+    let trees: Vec<(ProgramId, Vec<u32>)> = running
+        .iter()
+        .map(|(id, pid)| (*id, processes.tree(*pid)))
+        .collect();
+    let mut commands = vec![];
+    let mut errors = vec![];
+    for pid in owners {
+        match trees.iter().find(|(_, tree)| tree.contains(&pid)) {
+            Some((id, _)) if !commands.contains(id) => commands.push(*id),
+            Some(_) => {}
+            None => {
+                if unsafe { libc::kill(pid as i32, libc::SIGTERM) } != 0 {
+                    errors.push((pid, std::io::Error::last_os_error()));
+                }
+            }
+        }
     }
+    let result = match errors.first() {
+        None => Ok(()),
+        Some((_, first)) => {
+            let messages: Vec<String> = errors
+                .iter()
+                .map(|(pid, err)| format!("Cannot stop PID {}: {}", pid, err))
+                .collect();
+            Err(std::io::Error::new(first.kind(), messages.join(", ")))
+        }
+    };
+    (commands, result)
 }
 
-fn run_command<W: 'static + AsyncWrite + Send + std::marker::Unpin>(
+fn run_command(
     cmd: RunCommand,
     mut resp: Sender<RunResponse>,
-    log_sinks: Unclaimed<W>,
+    log_sinks: Unclaimed<LogSink>,
+    resubscribe: Sender<LogSink>,
+    scrollbacks: Scrollbacks,
+    statuses: Statuses,
 ) -> Result<oneshot::Sender<Kill>, ::tokio::io::Error> {
     let RunCommand {
         name,
         args,
         id,
         working_dir,
-        ..
+        env,
+        alias,
+        limits,
+        ports: _,
     } = cmd;
+    let alias = alias.trim().to_string();
     let mut command = tokio::process::Command::new(name);
     for arg in args.into_iter() {
         command.arg(arg);
@@ -158,31 +478,70 @@ fn run_command<W: 'static + AsyncWrite + Send + std::marker::Unpin>(
     if let Some(dir) = working_dir {
         command.current_dir(dir);
     }
+    command.envs(env);
+    // The program gets its own process group, so that killing it also stops
+    // the processes it starts, like the commands of a shell pipeline.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+        if !limits.is_empty() {
+            command.pre_exec(move || apply_limits(&limits));
+        }
+    }
     let mut child: Child = command
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn()?;
+        .spawn()
+        .map_err(|err| match err.raw_os_error() {
+            Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::EINVAL) if !limits.is_empty() => {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("{} (raising limits and niceness needs privileges)", err),
+                )
+            }
+            _ => err,
+        })?;
 
     let pid = child.id();
+    let started_at = SystemTime::now();
     log::info!("PID: {}", pid);
+    statuses.lock().unwrap().insert(
+        alias.clone(),
+        CommandStatus {
+            id,
+            alias: alias.clone(),
+            pid,
+            started_at: timestamp_millis(started_at),
+            stats: None,
+        },
+    );
 
     let mut resp_1 = resp.clone();
     tokio::spawn(async move {
         resp_1
             .send(RunResponse::Started(id, pid))
             .await
-            .die_on_err();
+            .log_dropped();
     });
 
     let stdout = child.stdout.take().unwrap();
     let stderr = child.stderr.take().unwrap();
+    let (exit_tx, exit_rx) = oneshot::channel();
     let mut log_forwarder = LogForward {
+        alias,
+        scrollbacks,
         stdout,
         stderr,
         pid,
+        started_at,
+        exit_status: exit_rx,
     };
     tokio::spawn(async move {
-        if let Err(err) = log_forwarder.run(log_sinks).await {
+        if let Err(err) = log_forwarder.run(log_sinks, resubscribe).await {
             log::error!("Error forwarding logs: {}", err);
         }
     });
@@ -195,14 +554,21 @@ fn run_command<W: 'static + AsyncWrite + Send + std::marker::Unpin>(
     };
 
     let _join_handle = tokio::spawn(async move {
-        match child.await {
+        let result = child.await;
+        // A restart may have taken the place of the program already.
+        statuses
+            .lock()
+            .unwrap()
+            .retain(|_, status| status.pid != pid);
+        match result {
             Ok(status) => {
+                let _ = exit_tx.send(status);
                 resp.send(RunResponse::Exited(id, status))
                     .await
-                    .die_on_err();
+                    .log_dropped();
             }
             Err(err) => {
-                resp.send(RunResponse::IoError(id, err)).await.die_on_err();
+                resp.send(RunResponse::IoError(id, err)).await.log_dropped();
             }
         }
     });
@@ -210,77 +576,484 @@ fn run_command<W: 'static + AsyncWrite + Send + std::marker::Unpin>(
     Ok(tx)
 }
 
+// A log client. Frames are queued and written out by a task of its own, so a
+// client that stops reading never holds up the log forwarder.
+pub struct LogSink {
+    subscription: Subscription,
+    frames: Sender<Vec<u8>>,
+    // Set when the client is dropped for falling behind.
+    overflowed: Arc<AtomicBool>,
+    // Set once the frames are no longer written, eg. the client hung up.
+    closed: Arc<AtomicBool>,
+    // Frames dropped since the queue filled up, with `Overflow::DropFrames`.
+    dropped: usize,
+}
+
+impl LogSink {
+    // Max number of frames queued for a client.
+    const QUEUE_LEN: usize = 256;
+    // How long the final frames of a run may wait for room in the queue.
+    const FINAL_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+    // How long a single write to the client may take.
+    const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    fn spawn<W: 'static + Send + AsyncRead + AsyncWrite + std::marker::Unpin>(
+        subscription: Subscription,
+        writer: W,
+    ) -> Self {
+        let (frames, queue) = mpsc::channel(Self::QUEUE_LEN);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let closed = Arc::new(AtomicBool::new(false));
+        let version = subscription.version;
+        tokio::spawn({
+            let overflowed = overflowed.clone();
+            let closed = closed.clone();
+            async move {
+                LogSink::write_frames(queue, writer, version, overflowed).await;
+                closed.store(true, Ordering::SeqCst);
+            }
+        });
+        Self {
+            subscription,
+            frames,
+            overflowed,
+            closed,
+            dropped: 0,
+        }
+    }
+
+    fn version(&self) -> Version {
+        self.subscription.version
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    // Queues the frame without waiting. Returns false if the sink should be
+    // dropped, either because the client is gone or because it cannot keep up.
+    fn offer(&mut self, frame: &[u8]) -> bool {
+        if frame.is_empty() {
+            return true;
+        }
+        match self.frames.try_send(frame.to_vec()) {
+            Ok(()) => {
+                if self.dropped > 0 {
+                    log::warn!(
+                        "Log client of {} caught up, {} frames were dropped",
+                        self.alias(),
+                        self.dropped
+                    );
+                    self.dropped = 0;
+                }
+                true
+            }
+            Err(TrySendError::Full(_)) => match self.subscription.overflow {
+                Overflow::DropFrames => {
+                    if self.dropped == 0 {
+                        log::warn!(
+                            "Log client of {} is too slow, dropping output",
+                            self.alias()
+                        );
+                    }
+                    self.dropped += 1;
+                    true
+                }
+                Overflow::Disconnect => {
+                    log::warn!("Log client of {} is too slow, disconnecting", self.alias());
+                    self.overflowed.store(true, Ordering::SeqCst);
+                    false
+                }
+            },
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    // Queues the frame, waiting a little for room. Only used once the program's
+    // outputs are drained, where waiting does not hold up the program.
+    async fn send(&mut self, frame: &[u8]) -> bool {
+        if frame.is_empty() {
+            return true;
+        }
+        match timeout(Self::FINAL_FRAME_TIMEOUT, self.frames.send(frame.to_vec())).await {
+            Ok(Ok(())) => true,
+            Ok(Err(_)) => false,
+            Err(_) => {
+                self.overflowed.store(true, Ordering::SeqCst);
+                false
+            }
+        }
+    }
+
+    fn alias(&self) -> &str {
+        self.subscription.alias.trim()
+    }
+
+    async fn write_frames<W: AsyncRead + AsyncWrite + std::marker::Unpin>(
+        mut queue: Receiver<Vec<u8>>,
+        writer: W,
+        version: Version,
+        overflowed: Arc<AtomicBool>,
+    ) {
+        let (mut reader, mut writer) = tokio::io::split(writer);
+        // Clients send nothing after their headers, reading only tells when
+        // they hang up.
+        let mut ignored = [0; 64];
+        loop {
+            let frame = tokio::select! {
+                frame = queue.recv() => match frame {
+                    Some(frame) => frame,
+                    None => break,
+                },
+                read = reader.read(&mut ignored) => match read {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => continue,
+                },
+            };
+            match timeout(Self::WRITE_TIMEOUT, writer.write_all(&frame)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    log::error!("write log error: {}", err);
+                    return;
+                }
+                // The client stopped reading altogether, hang up on it.
+                Err(_) => return,
+            }
+        }
+        if overflowed.load(Ordering::SeqCst) {
+            let message = "Disconnected for not reading outputs fast enough";
+            let frame = Event::Error(message).frame(version);
+            let _ = timeout(Self::WRITE_TIMEOUT, writer.write_all(&frame)).await;
+        }
+        let _ = timeout(Self::WRITE_TIMEOUT, writer.shutdown()).await;
+    }
+}
+
 struct LogForward {
+    alias: String,
+    scrollbacks: Scrollbacks,
     stdout: ChildStdout,
     stderr: ChildStderr,
     pid: u32,
+    started_at: SystemTime,
+    exit_status: oneshot::Receiver<ExitStatus>,
 }
 
 impl LogForward {
-    async fn run<W: AsyncWrite + std::marker::Unpin>(
+    // Outputs longer than a frame can hold are split by `Event::encode`.
+    const CHUNK_SIZE: usize = 8192;
+
+    async fn run(
         &mut self,
-        new_sinks: Unclaimed<W>,
+        new_sinks: Unclaimed<LogSink>,
+        mut resubscribe: Sender<LogSink>,
     ) -> tokio::io::Result<()> {
-        const FRAME_HEADER_LEN: usize = size_of::<u8>() + size_of::<u16>();
+        let mut out_buf: [u8; Self::CHUNK_SIZE] = [0; Self::CHUNK_SIZE];
+        let mut err_buf: [u8; Self::CHUNK_SIZE] = [0; Self::CHUNK_SIZE];
+        // For sinks asking for whole lines, and the scrollback.
+        let mut out_lines = LineFramer::default();
+        let mut err_lines = LineFramer::default();
 
-        let mut out_buf: [u8; 1024] = [0; 1024];
-        let mut err_buf: [u8; 1024] = [0; 1024];
-        out_buf[0] = STDOUT_TAG;
-        err_buf[0] = STDERR_TAG;
+        let mut sinks: Vec<LogSink> = vec![];
+        // Keep draining until both pipes are closed, so the last outputs do
+        // not get lost when one of them closes first.
+        let mut stdout_open = true;
+        let mut stderr_open = true;
 
-        let mut sinks: Vec<Option<W>> = vec![];
-
-        let mut buf: &[u8];
-
-        loop {
-            let len = tokio::select! {
-                len = self.stdout.read(&mut out_buf[FRAME_HEADER_LEN..]) => {
-                    let len = len?;
-                    (&mut out_buf[1..FRAME_HEADER_LEN]).clone_from_slice(&(len as u16).to_le_bytes());
-                    buf = &out_buf[0..len+FRAME_HEADER_LEN];
-                    len
+        while stdout_open || stderr_open {
+            let flush_at = match (out_lines.deadline, err_lines.deadline) {
+                (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+                (a, b) => a.or(b),
+            };
+            // `None` when partial lines are due to be flushed.
+            let event = tokio::select! {
+                len = self.stdout.read(&mut out_buf), if stdout_open => {
+                    Some(Event::Stdout(&out_buf[..len?]))
+                }
+                len = self.stderr.read(&mut err_buf), if stderr_open => {
+                    Some(Event::Stderr(&err_buf[..len?]))
                 }
-                len = self.stderr.read(&mut err_buf[FRAME_HEADER_LEN..]) => {
-                    let len = len?;
-                    (&mut err_buf[1..FRAME_HEADER_LEN]).clone_from_slice(&(len as u16).to_le_bytes());
-                    buf = &err_buf[0..len+FRAME_HEADER_LEN];
-                    len
+                _ = delay_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                    None
                 }
             };
-            if len == 0 {
-                sinks.clear();
-                break;
-            }
-            {
-                let mut new_sinks = new_sinks.lock().expect("mutex error");
-                sinks.extend(new_sinks.drain(..).map(Some));
-            }
-            for sink in sinks.iter_mut() {
-                let mut sink_taken = sink.take().unwrap();
-                if let Err(err) = LogForward::write_frame(&mut sink_taken, buf).await {
-                    log::error!("write log error: {}", err);
-                    drop(sink_taken);
-                    break;
+            self.claim_sinks(&new_sinks, &mut sinks);
+            match event {
+                Some(Event::Stdout(&[])) => {
+                    stdout_open = false;
+                    out_lines.flush(|line| self.forward_line(&mut sinks, Event::Stdout(line)));
+                }
+                Some(Event::Stderr(&[])) => {
+                    stderr_open = false;
+                    err_lines.flush(|line| self.forward_line(&mut sinks, Event::Stderr(line)));
+                }
+                Some(event) => {
+                    LogForward::broadcast(&mut sinks, event, Framing::Chunks);
+                    match event {
+                        Event::Stdout(bytes) => out_lines.push(bytes, |line| {
+                            self.forward_line(&mut sinks, Event::Stdout(line))
+                        }),
+                        Event::Stderr(bytes) => err_lines.push(bytes, |line| {
+                            self.forward_line(&mut sinks, Event::Stderr(line))
+                        }),
+                        _ => {}
+                    }
+                }
+                None => {
+                    let now = Instant::now();
+                    if out_lines.deadline.filter(|at| *at <= now).is_some() {
+                        out_lines.flush(|line| self.forward_line(&mut sinks, Event::Stdout(line)));
+                    }
+                    if err_lines.deadline.filter(|at| *at <= now).is_some() {
+                        err_lines.flush(|line| self.forward_line(&mut sinks, Event::Stderr(line)));
+                    }
                 }
-                *sink = Some(sink_taken);
             }
-            sinks.retain(Option::is_some);
         }
 
         log::info!("PID: {} has not more stdout/stderr outputs", self.pid);
 
+        let exit_status = (&mut self.exit_status).await;
+        // Clients attached since the last output still get told about the exit,
+        // later ones will find the queue closed.
+        new_sinks.lock().expect("mutex error").closed = true;
+        self.claim_sinks(&new_sinks, &mut sinks);
+        let message;
+        let event = match exit_status {
+            Ok(status) => Event::Exited(status),
+            Err(_) => {
+                message = format!("Cannot get exit status of PID: {}", self.pid);
+                Event::Error(&message)
+            }
+        };
+        let now = SystemTime::now();
+        for mut sink in sinks.drain(..) {
+            let delivered = sink.send(&event.frame_at(sink.version(), now)).await;
+            if delivered && sink.subscription.follow_restarts {
+                let _ = resubscribe.send(sink).await;
+            }
+        }
+
         Ok(())
     }
 
-    async fn write_frame<W: AsyncWrite + std::marker::Unpin>(
-        out: &mut W,
-        contents: &[u8],
-    ) -> tokio::io::Result<()> {
-        out.write_all(contents).await?;
-        Ok(())
+    fn claim_sinks(&self, new_sinks: &Unclaimed<LogSink>, sinks: &mut Vec<LogSink>) {
+        let claimed: Vec<_> = {
+            let mut new_sinks = new_sinks.lock().expect("mutex error");
+            new_sinks.sinks.drain(..).collect()
+        };
+        for mut sink in claimed {
+            // Let v2 clients know the PID and when the program was started.
+            let started = Event::Started(self.pid).frame_at(sink.version(), self.started_at);
+            if sink.offer(&started) {
+                sinks.push(sink);
+            }
+        }
+    }
+
+    // Sends a whole line to the sinks asking for lines, and keeps it in the
+    // scrollback of the command.
+    fn forward_line(&self, sinks: &mut Vec<LogSink>, event: Event<'_>) {
+        let (stream, line) = match event {
+            Event::Stdout(line) => (Stream::Stdout, line),
+            Event::Stderr(line) => (Stream::Stderr, line),
+            _ => return,
+        };
+        if let Some(scrollback) = self
+            .scrollbacks
+            .lock()
+            .expect("mutex error")
+            .get_mut(&self.alias)
+        {
+            scrollback.push(LogLine {
+                timestamp: timestamp_millis(SystemTime::now()),
+                stream,
+                pid: self.pid,
+                text: line.strip_suffix(b"\n").unwrap_or(line).to_vec(),
+            });
+        }
+        LogForward::broadcast(sinks, event, Framing::Lines)
+    }
+
+    // Queues the event for every sink using the given framing, dropping the
+    // ones that are gone or too slow.
+    fn broadcast(sinks: &mut Vec<LogSink>, event: Event<'_>, framing: Framing) {
+        if sinks
+            .iter()
+            .all(|sink| sink.subscription.framing != framing)
+        {
+            return;
+        }
+        let now = SystemTime::now();
+        let v1_frame = event.frame_at(Version::V1, now);
+        let v2_frame = event.frame_at(Version::V2, now);
+
+        let mut i = 0;
+        while i < sinks.len() {
+            let sink = &mut sinks[i];
+            if sink.subscription.framing != framing {
+                i += 1;
+                continue;
+            }
+            let frame = match sink.version() {
+                Version::V1 => &v1_frame,
+                Version::V2 => &v2_frame,
+            };
+            if sink.offer(frame) {
+                i += 1;
+            } else {
+                drop(sinks.swap_remove(i));
+            }
+        }
     }
 }
 
+// Splits outputs into lines. Lines longer than `MAX_LINE_LEN` are cut, and
+// partial lines are emitted once no line break showed up for `FLUSH_TIMEOUT`.
+#[derive(Debug, Default)]
+struct LineFramer {
+    pending: Vec<u8>,
+    // When the pending partial line is due.
+    deadline: Option<Instant>,
+}
+
+impl LineFramer {
+    const MAX_LINE_LEN: usize = 16 * 1024;
+    const FLUSH_TIMEOUT: Duration = Duration::from_millis(100);
+
+    // Calls `emit` with every completed line, line break included.
+    fn push<F: FnMut(&[u8])>(&mut self, mut bytes: &[u8], mut emit: F) {
+        while !bytes.is_empty() {
+            let room = Self::MAX_LINE_LEN - self.pending.len();
+            let (line, complete) = match bytes.iter().take(room).position(|b| *b == b'\n') {
+                Some(pos) => (&bytes[..=pos], true),
+                None if bytes.len() >= room => (&bytes[..room], true),
+                None => (bytes, false),
+            };
+            bytes = &bytes[line.len()..];
+            if !complete {
+                self.pending.extend_from_slice(line);
+            } else if self.pending.is_empty() {
+                emit(line);
+            } else {
+                self.pending.extend_from_slice(line);
+                emit(&self.pending);
+                self.pending.clear();
+            }
+        }
+        self.deadline = if self.pending.is_empty() {
+            None
+        } else {
+            self.deadline
+                .or_else(|| Some(Instant::now() + Self::FLUSH_TIMEOUT))
+        };
+    }
+
+    // Emits the pending partial line, if any.
+    fn flush<F: FnMut(&[u8])>(&mut self, mut emit: F) {
+        if !self.pending.is_empty() {
+            emit(&self.pending);
+            self.pending.clear();
+        }
+        self.deadline = None;
+    }
+}
+
+// Reports the resource usage of the running programs, until the receiver of
+// the responses is gone. All programs are sampled from one read of /proc.
+async fn sample_stats(mut resp: Sender<RunResponse>, statuses: Statuses) {
+    let mut samplers: HashMap<u32, Sampler> = HashMap::new();
+    let mut interval = tokio::time::interval(STATS_INTERVAL);
+    loop {
+        interval.tick().await;
+        let running: Vec<(ProgramId, u32)> = statuses
+            .lock()
+            .unwrap()
+            .values()
+            .map(|status| (status.id, status.pid))
+            .collect();
+        if running.is_empty() {
+            samplers.clear();
+            continue;
+        }
+        let mut previous = std::mem::take(&mut samplers);
+        let sampled = tokio::task::spawn_blocking(move || {
+            let processes = ProcessTable::read();
+            let mut samples = vec![];
+            for (id, pid) in running {
+                let mut sampler = previous.remove(&pid).unwrap_or_else(|| Sampler::new(pid));
+                if let Some(stats) = sampler.sample(&processes) {
+                    samples.push((id, pid, stats, sampler));
+                }
+            }
+            samples
+        })
+        .await;
+        let sampled = match sampled {
+            Ok(sampled) => sampled,
+            Err(err) => {
+                log::error!("Error sampling stats: {}", err);
+                continue;
+            }
+        };
+        let mut samples = vec![];
+        {
+            let mut statuses = statuses.lock().unwrap();
+            for (id, pid, stats, sampler) in sampled {
+                // Skip programs which exited in the meantime.
+                if let Some(status) = statuses.values_mut().find(|status| status.pid == pid) {
+                    status.stats = Some(stats.clone());
+                    samples.push((id, stats));
+                    samplers.insert(pid, sampler);
+                }
+            }
+        }
+        for (id, stats) in samples {
+            if resp.send(RunResponse::Stats(id, stats)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// Runs in the child before exec, so only makes async-signal-safe calls.
+fn apply_limits(limits: &Limits) -> std::io::Result<()> {
+    let megabytes = |size: u64| size.saturating_mul(1024 * 1024);
+    let resources = [
+        (libc::RLIMIT_AS, limits.memory_mb.map(megabytes)),
+        (libc::RLIMIT_NOFILE, limits.open_files),
+        (libc::RLIMIT_CPU, limits.cpu_seconds),
+        (libc::RLIMIT_CORE, limits.core_mb.map(megabytes)),
+    ];
+    for (resource, limit) in resources.iter() {
+        let limit = match limit {
+            Some(limit) => *limit as libc::rlim_t,
+            None => continue,
+        };
+        let mut rlimit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(*resource, &mut rlimit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // Going over the hard limit fails without privileges.
+        rlimit.rlim_cur = limit;
+        if rlimit.rlim_max != libc::RLIM_INFINITY && rlimit.rlim_max < limit {
+            rlimit.rlim_max = limit;
+        }
+        if unsafe { libc::setrlimit(*resource, &rlimit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    if let Some(nice) = limits.nice {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
 #[derive(Copy, Clone)]
 struct Kill;
 
@@ -291,17 +1064,24 @@ struct KillableChild {
 }
 
 impl KillableChild {
+    // Signals the whole process group of the program.
     fn kill(&mut self) -> std::io::Result<()> {
         if self.killed {
             return Ok(());
         }
-        let success = unsafe { libc::kill(self.child.id() as i32, libc::SIGTERM) };
-        if success != 0 {
-            // Reads from errno
-            return Err(std::io::Error::last_os_error());
-        } else {
-            Ok(())
-        }
+        signal_group(self.child.id(), libc::SIGTERM)?;
+        // A paused program only handles the signal once resumed.
+        signal_group(self.child.id(), libc::SIGCONT)
+    }
+}
+
+fn signal_group(pid: u32, signal: i32) -> std::io::Result<()> {
+    let success = unsafe { libc::kill(-(pid as i32), signal) };
+    if success != 0 {
+        // Reads from errno
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
     }
 }
 
@@ -328,30 +1108,551 @@ impl Future for KillableChild {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
     use tokio::sync::mpsc::channel;
 
+    use crate::constants::{ERROR_TAG, EXITED_TAG, STARTED_TAG, STDOUT_TAG};
     use crate::model::program_id;
+    use crate::protocol::{EXIT_CODE, V2_HEADER_LEN};
+    use crate::scrollback::SearchQuery;
 
-    #[tokio::test(threaded_scheduler)]
-    async fn test_run_then_kill() {
-        let (mut tx, rx) = channel(128);
-        let (_, rx_unused) = channel::<(String, Vec<u8>)>(128);
-        let (mut spawner, _) = Spawner::new(rx, rx_unused);
+    fn frame_tags(mut bytes: &[u8]) -> Vec<u8> {
+        let mut tags = vec![];
+        while bytes.len() >= V2_HEADER_LEN {
+            let len = u16::from_le_bytes([bytes[9], bytes[10]]) as usize;
+            tags.push(bytes[0]);
+            bytes = &bytes[V2_HEADER_LEN + len..];
+        }
+        tags
+    }
+
+    // Collects everything written to it, so tests can inspect the frames.
+    #[derive(Debug, Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl AsyncWrite for SharedSink {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
 
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Clients never hang up.
+    impl AsyncRead for SharedSink {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    // Never accepts any bytes, like a client that stopped reading.
+    #[derive(Debug)]
+    struct StalledSink;
+
+    impl AsyncRead for StalledSink {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for StalledSink {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    // How long a test waits for a response of the spawner.
+    const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn command(alias: &str, name: &str, args: &[&str]) -> RunCommand {
+        RunCommand {
+            id: program_id(0),
+            alias: alias.to_string(),
+            name: name.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            working_dir: None,
+            env: vec![],
+            limits: Limits::default(),
+            ports: vec![],
+        }
+    }
+
+    fn subscription(alias: &str) -> Subscription {
+        Subscription {
+            alias: alias.to_string(),
+            version: Version::V2,
+            wait: false,
+            follow_restarts: false,
+            overflow: Overflow::Disconnect,
+            framing: Framing::Chunks,
+        }
+    }
+
+    // A running spawner.
+    struct TestSpawner<W> {
+        requests: Sender<RunRequest>,
+        sinks: Sender<(Subscription, W)>,
+        responses: Receiver<RunResponse>,
+        scrollbacks: Scrollbacks,
+        statuses: Statuses,
+    }
+
+    fn start_spawner<W: 'static + Send + AsyncRead + AsyncWrite + std::marker::Unpin>(
+    ) -> TestSpawner<W> {
+        let (requests, rx) = channel(128);
+        let (sinks, sinks_rx) = channel(128);
+        let (mut spawner, responses) = Spawner::new(rx, sinks_rx);
+        let scrollbacks = spawner.scrollbacks();
+        let statuses = spawner.statuses();
         tokio::spawn(async move {
             spawner.run().await.unwrap();
         });
+        TestSpawner {
+            requests,
+            sinks,
+            responses,
+            scrollbacks,
+            statuses,
+        }
+    }
 
-        tx.send(RunRequest::Run(RunCommand {
-            id: program_id(0),
-            alias: "cat".to_string(),
-            name: "cat".to_string(),
-            args: vec![],
-            working_dir: None,
-        }))
+    impl<W> TestSpawner<W> {
+        async fn send(&mut self, request: RunRequest) {
+            self.requests.send(request).await.unwrap();
+        }
+
+        async fn subscribe(&mut self, subscription: Subscription, writer: W) {
+            self.sinks
+                .send((subscription, writer))
+                .await
+                .unwrap_or_else(|_| panic!("the spawner stopped"));
+        }
+
+        // The first response `pick` returns something for, skipping the
+        // others.
+        async fn expect<T, F>(&mut self, mut pick: F) -> T
+        where
+            F: FnMut(RunResponse) -> Option<T>,
+        {
+            let responses = &mut self.responses;
+            timeout(RESPONSE_TIMEOUT, async {
+                loop {
+                    if let Some(picked) = pick(responses.recv().await.unwrap()) {
+                        return picked;
+                    }
+                }
+            })
+            .await
+            .expect("timed out waiting for the spawner")
+        }
+
+        async fn exited(&mut self) -> ExitStatus {
+            self.expect(|response| match response {
+                RunResponse::Exited(_, status) => Some(status),
+                RunResponse::IoError(_, err) => panic!("{}", err),
+                _ => None,
+            })
+            .await
+        }
+
+        fn outputs(&self, alias: &str) -> Vec<Vec<u8>> {
+            let query = SearchQuery {
+                pattern: None,
+                since: None,
+                until: None,
+                limit: 10,
+            };
+            self.scrollbacks.lock().unwrap()[alias]
+                .search(&query)
+                .into_iter()
+                .map(|line| line.text)
+                .collect()
+        }
+    }
+
+    // Polls until the condition holds, for state changed outside the spawner.
+    async fn eventually<F: FnMut() -> bool>(mut condition: F) -> bool {
+        for _ in 0..100 {
+            if condition() {
+                return true;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        condition()
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_run_then_kill() {
+        let mut spawner = start_spawner::<SharedSink>();
+        spawner
+            .send(RunRequest::Run(command("cat", "cat", &[])))
+            .await;
+        spawner.send(RunRequest::Kill(program_id(0))).await;
+        spawner.exited().await;
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_exit_frame() {
+        let mut spawner = start_spawner();
+        let exit = command("exit", "sh", &["-c", "sleep 0.2; exit 3"]);
+        spawner.send(RunRequest::Run(exit)).await;
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        let sink = SharedSink::default();
+        spawner.subscribe(subscription("exit"), sink.clone()).await;
+        assert_eq!(spawner.exited().await.code(), Some(3));
+
+        let expected_len = 2 * V2_HEADER_LEN + 4 + 5;
+        eventually(|| sink.0.lock().unwrap().len() >= expected_len).await;
+        let bytes = sink.0.lock().unwrap().clone();
+        assert_eq!(bytes.len(), expected_len);
+        let exited = &bytes[V2_HEADER_LEN + 4..];
+        assert_eq!(exited[0], EXITED_TAG);
+        assert_eq!(&exited[V2_HEADER_LEN..], &[EXIT_CODE, 3, 0, 0, 0]);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_kill_pipeline() {
+        let mut spawner = start_spawner();
+        let pipeline = command("pipeline", "sh", &["-c", "sleep 30 | cat"]);
+        spawner.send(RunRequest::Run(pipeline)).await;
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        let sink = SharedSink::default();
+        spawner
+            .subscribe(subscription("pipeline"), sink.clone())
+            .await;
+        spawner.send(RunRequest::Kill(program_id(0))).await;
+        spawner.exited().await;
+
+        // The exit is sent once `cat` closes stdout, which it only does when
+        // killed along with the shell.
+        let started_len = V2_HEADER_LEN + 4;
+        eventually(|| sink.0.lock().unwrap().len() > started_len).await;
+        let bytes = sink.0.lock().unwrap().clone();
+        assert!(bytes.len() > started_len);
+        assert_eq!(bytes[started_len], EXITED_TAG);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_wait_and_follow_restarts() {
+        let mut spawner = start_spawner();
+
+        // Subscribe before the command is started.
+        let sink = SharedSink::default();
+        let waiting = Subscription {
+            wait: true,
+            follow_restarts: true,
+            ..subscription("echo")
+        };
+        spawner.subscribe(waiting, sink.clone()).await;
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        for _ in 0..2 {
+            let echo = RunCommand {
+                env: vec![("GREETING".to_string(), "hi".to_string())],
+                ..command("echo", "sh", &["-c", "sleep 0.2; echo $GREETING"])
+            };
+            spawner.send(RunRequest::Run(echo)).await;
+            spawner.exited().await;
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+
+        let run = [STARTED_TAG, STDOUT_TAG, EXITED_TAG];
+        let expected: Vec<u8> = run.iter().chain(run.iter()).copied().collect();
+        assert_eq!(frame_tags(&sink.0.lock().unwrap()), expected);
+
+        // Outputs of both runs are kept.
+        assert_eq!(spawner.outputs("echo"), vec![b"hi", b"hi"]);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_stats() {
+        let mut spawner = start_spawner::<SharedSink>();
+        // `wait` keeps the shell from running the last command in its place.
+        let sleep = command("sleep", "sh", &["-c", "sleep 10 & sleep 10; wait"]);
+        spawner.send(RunRequest::Run(sleep)).await;
+
+        // The shell and both of its children.
+        let mut pid = None;
+        spawner
+            .expect(|response| match response {
+                RunResponse::Started(_, started) => {
+                    pid = Some(started);
+                    None
+                }
+                RunResponse::Stats(_, stats) if stats.threads == 3 => Some(()),
+                RunResponse::Exited(..) => panic!("exited early"),
+                _ => None,
+            })
+            .await;
+        {
+            let statuses = spawner.statuses.lock().unwrap();
+            let status = &statuses["sleep"];
+            assert_eq!(Some(status.pid), pid);
+            assert!(status.stats.is_some());
+        }
+
+        spawner.send(RunRequest::Kill(program_id(0))).await;
+        spawner.exited().await;
+        assert!(spawner.statuses.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_signal() {
+        let mut spawner = start_spawner::<SharedSink>();
+        spawner
+            .send(RunRequest::Run(command("sleep", "sleep", &["10"])))
+            .await;
+        let pid = spawner
+            .expect(|response| match response {
+                RunResponse::Started(_, pid) => Some(pid),
+                response => panic!("unexpected response {:?}", response),
+            })
+            .await;
+
+        let state = || {
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+            stat[stat.rfind(')').unwrap() + 2..].chars().next().unwrap()
+        };
+        spawner
+            .send(RunRequest::Signal(program_id(0), libc::SIGSTOP, None))
+            .await;
+        let result = spawner
+            .expect(|response| match response {
+                RunResponse::Signaled(_, libc::SIGSTOP, result) => Some(result),
+                RunResponse::Exited(..) => panic!("exited early"),
+                _ => None,
+            })
+            .await;
+        assert!(result.is_ok());
+        // The signal is delivered asynchronously.
+        assert!(eventually(|| state() == 'T').await);
+
+        // Paused programs are killed too.
+        spawner.send(RunRequest::Kill(program_id(0))).await;
+        spawner.exited().await;
+
+        let (reply, reply_rx) = oneshot::channel();
+        spawner
+            .send(RunRequest::Signal(program_id(0), libc::SIGHUP, Some(reply)))
+            .await;
+        let result = spawner
+            .expect(|response| match response {
+                RunResponse::Signaled(_, _, result) => Some(result),
+                _ => None,
+            })
+            .await;
+        assert!(result.is_err());
+        // The sender of the request is told as well.
+        let replied = reply_rx.await.unwrap();
+        assert_eq!(replied.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_port_in_use() {
+        let mut spawner = start_spawner::<SharedSink>();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = RunCommand {
+            ports: vec![port],
+            ..command("server", "true", &[])
+        };
+        spawner.send(RunRequest::Run(server.clone())).await;
+
+        let conflict = spawner
+            .expect(|response| match response {
+                RunResponse::PortInUse(_, conflict) => Some(conflict),
+                response => panic!("expected a port conflict, got {:?}", response),
+            })
+            .await;
+        assert_eq!(conflict.port, port);
+        let pids: Vec<u32> = conflict.owners.iter().map(|(pid, _)| *pid).collect();
+        assert_eq!(pids, vec![std::process::id()]);
+
+        // Started once the port is free.
+        drop(listener);
+        spawner.send(RunRequest::Run(server)).await;
+        spawner
+            .expect(|response| match response {
+                RunResponse::Started(..) | RunResponse::Exited(..) => Some(()),
+                response => panic!("expected a start, got {:?}", response),
+            })
+            .await;
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_kill_port_owners() {
+        let mut spawner = start_spawner::<SharedSink>();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        // A port freed in the meantime has no owners left to stop.
+        spawner
+            .send(RunRequest::KillPortOwners(program_id(0), port))
+            .await;
+        let result = spawner
+            .expect(|response| match response {
+                RunResponse::PortOwnersKilled(_, result) => Some(result),
+                response => panic!("unexpected response {:?}", response),
+            })
+            .await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_limits() {
+        let mut spawner = start_spawner::<SharedSink>();
+        // Lowering the niceness needs privileges, stay above the one of the tests.
+        let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS as _, 0) };
+        let nice = (nice + 5).min(19);
+        let limited = RunCommand {
+            limits: Limits {
+                open_files: Some(64),
+                core_mb: Some(0),
+                nice: Some(nice),
+                ..Limits::default()
+            },
+            ..command("limited", "sh", &["-c", "ulimit -n; ulimit -c; nice"])
+        };
+        spawner.send(RunRequest::Run(limited)).await;
+        spawner.exited().await;
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            spawner.outputs("limited"),
+            vec![b"64".to_vec(), b"0".to_vec(), nice.to_string().into_bytes()]
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_stalled_sink() {
+        let mut spawner = start_spawner();
+        let waiting = Subscription {
+            wait: true,
+            ..subscription("zeros")
+        };
+        spawner.subscribe(waiting, StalledSink).await;
+
+        // Plenty more output than fits in a sink's queue.
+        let total = (1024 * 1024).to_string();
+        let zeros = command("zeros", "head", &["-c", &total, "/dev/zero"]);
+        spawner.send(RunRequest::Run(zeros)).await;
+
+        // Times out when the program is held up by the stalled log client.
+        assert!(spawner.exited().await.success());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_slow_sink_dropped_frames() {
+        let (frames, queue) = mpsc::channel(1);
+        let mut sink = LogSink {
+            subscription: Subscription {
+                overflow: Overflow::DropFrames,
+                ..subscription("slow")
+            },
+            frames,
+            overflowed: Arc::new(AtomicBool::new(false)),
+            closed: Arc::new(AtomicBool::new(false)),
+            dropped: 0,
+        };
+        assert!(sink.offer(b"first"));
+        assert!(sink.offer(b"second"));
+        assert!(sink.offer(b"third"));
+        assert_eq!(sink.dropped, 2);
+
+        sink.subscription.overflow = Overflow::Disconnect;
+        assert!(!sink.offer(b"fourth"));
+        assert!(sink.overflowed.load(Ordering::SeqCst));
+
+        let stalled = sink.overflowed.clone();
+        let out = SharedSink::default();
+        drop(sink);
+        LogSink::write_frames(queue, out.clone(), Version::V2, stalled).await;
+        let bytes = out.0.lock().unwrap().clone();
+        assert!(bytes.starts_with(b"first"));
+        assert_eq!(frame_tags(&bytes[5..]), vec![ERROR_TAG]);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_sink_closed_on_hangup() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let server = tokio::net::TcpStream::from_std(server).unwrap();
+        let waiting = Subscription {
+            wait: true,
+            ..subscription("later")
+        };
+        let sink = LogSink::spawn(waiting, server);
+        assert!(!sink.is_closed());
+
+        drop(client);
+        timeout(Duration::from_secs(5), async {
+            while !sink.is_closed() {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        })
         .await
-        .unwrap();
+        .expect("the hangup was not noticed");
+    }
+
+    #[test]
+    fn test_line_framer() {
+        let mut lines = LineFramer::default();
+        let mut emitted: Vec<Vec<u8>> = vec![];
+        lines.push(b"first li", |line| emitted.push(line.to_vec()));
+        assert!(lines.deadline.is_some());
+        lines.push(b"ne\nsecond\n\nthi", |line| emitted.push(line.to_vec()));
+        lines.flush(|line| emitted.push(line.to_vec()));
+        assert!(lines.deadline.is_none());
+
+        let long = vec![b'x'; LineFramer::MAX_LINE_LEN + 10];
+        lines.push(&long, |line| emitted.push(line.to_vec()));
+        lines.push(b"\n", |line| emitted.push(line.to_vec()));
 
-        tx.send(RunRequest::Kill(program_id(0))).await.unwrap();
+        assert_eq!(
+            emitted,
+            vec![
+                b"first line\n".to_vec(),
+                b"second\n".to_vec(),
+                b"\n".to_vec(),
+                b"thi".to_vec(),
+                long[..LineFramer::MAX_LINE_LEN].to_vec(),
+                b"xxxxxxxxxx\n".to_vec(),
+            ]
+        );
     }
 }
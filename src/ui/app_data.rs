@@ -1,20 +1,96 @@
 use std::iter::Iterator;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::Arc;
 
 use druid::lens::{self, LensExt};
 use druid::{im, Data, Lens};
+use indexmap::IndexMap;
+use regex::bytes::Regex;
 use tokio::sync::mpsc;
 
-use crate::model::{ProgramId, ProgramIdGen, RunCommand, RunRequest, RunResponse};
-use crate::persist::{dump_entries, CommandEntry, Commands};
+use crate::import::import_file;
+use crate::model::{
+    signal_name, PortConflict, ProcessStats, ProgramId, ProgramIdGen, RunCommand, RunRequest,
+    RunResponse,
+};
+use crate::persist::{
+    dump_entries, dump_project, find_project, load_project, CommandEntry, CommandOverride,
+    Commands, Limits, OpenProject, Shell, Template, PROJECT_FILE,
+};
+use crate::scrollback::{Scrollbacks, SearchQuery};
 
 #[derive(Clone, Data, Lens)]
 pub struct AppData {
     __id_counter: u32,
     pub entries: im::Vector<Entry>,
     pub edit_entry: EditState<EntryData>,
+    pub log_view: Option<LogView>,
+    pub import: Option<ImportView>,
+    // Profile the commands start with.
+    pub profile: Option<String>,
+    // Error from the last attempt to save the config file.
+    pub save_error: Option<String>,
+    // Error from the last attempt to read the config file.
+    pub config_error: Option<String>,
+    // What the file picked in the open panel is for, the panel answers all
+    // requests with the same command.
+    pub open_dialog: Option<OpenDialog>,
 
     #[data(ignore)]
     pub req_chan: mpsc::Sender<RunRequest>,
+    #[data(ignore)]
+    pub scrollbacks: Scrollbacks,
+    #[data(ignore)]
+    pub project_root: OpenProject,
+    // Defaults and variables of the config file, for new entries.
+    #[data(ignore)]
+    pub template: Arc<Template>,
+}
+
+#[derive(Copy, Clone, Data, Debug, Eq, PartialEq)]
+pub enum OpenDialog {
+    // Commands are imported from the picked file.
+    Import,
+    // The picked directory is opened as a project.
+    Project,
+}
+
+// Recent outputs of a command, searched from its scrollback.
+#[derive(Clone, Default, Data, Lens, Eq, PartialEq)]
+pub struct LogView {
+    pub(super) alias: String,
+    pub(super) query: String,
+    pub(super) lines: im::Vector<String>,
+    pub(super) error: Option<String>,
+}
+
+impl LogView {
+    const MAX_LINES: usize = 500;
+
+    pub(super) fn new(alias: String) -> Self {
+        Self {
+            alias,
+            ..Self::default()
+        }
+    }
+}
+
+// Commands found in a file, to pick from before they are added.
+#[derive(Clone, Default, Data, Lens, Eq, PartialEq)]
+pub struct ImportView {
+    pub(super) source: String,
+    pub(super) entries: im::Vector<ImportEntry>,
+    pub(super) error: Option<String>,
+}
+
+#[derive(Clone, Data, Lens, Eq, PartialEq)]
+pub struct ImportEntry {
+    pub(super) selected: bool,
+    // The alias is in use, the command cannot be added.
+    pub(super) exists: bool,
+    pub(super) data: EntryData,
 }
 
 #[derive(Clone, Data, Eq, PartialEq)]
@@ -83,30 +159,62 @@ impl ProgramIdGen for AppData {
 }
 
 impl AppData {
-    pub fn new(req_chan: mpsc::Sender<RunRequest>) -> Self {
+    pub fn new(req_chan: mpsc::Sender<RunRequest>, scrollbacks: Scrollbacks) -> Self {
         Self {
             __id_counter: 0,
             req_chan,
+            scrollbacks,
+            project_root: OpenProject::default(),
+            template: Arc::default(),
             edit_entry: EditState::None,
+            log_view: None,
+            import: None,
+            profile: None,
+            save_error: None,
+            config_error: None,
+            open_dialog: None,
             entries: im::vector![],
         }
     }
 
-    pub fn from_commands(commands: Commands, req_chan: mpsc::Sender<RunRequest>) -> Self {
+    pub fn from_commands(
+        commands: Commands,
+        req_chan: mpsc::Sender<RunRequest>,
+        scrollbacks: Scrollbacks,
+    ) -> Self {
+        let template = Arc::new(commands.template());
         let entries = commands
-            .into_iter()
-            .map(EntryData::from)
-            .map(Entry::new)
+            .iter()
+            .map(|(alias, entry)| {
+                Entry::new(EntryData::from_commands(&commands, &template, alias, entry))
+            })
             .collect();
         Self {
             __id_counter: 0,
             req_chan,
+            scrollbacks,
+            project_root: OpenProject::default(),
+            template,
             edit_entry: EditState::None,
+            log_view: None,
+            import: None,
+            profile: None,
+            save_error: None,
+            config_error: None,
+            open_dialog: None,
             entries,
         }
     }
 
-    pub fn done_editing(&mut self) {
+    // Keeps the form open when the entry cannot be saved, the error shows in
+    // its preview. Returns whether the entries changed.
+    pub fn done_editing(&mut self) -> bool {
+        if let EditState::New(ref data) | EditState::Edit(_, ref data) = self.edit_entry {
+            if let Err(err) = data.validate() {
+                self.save_error = Some(format!("Cannot save {}: {}", data.alias, err));
+                return false;
+            }
+        }
         match self.edit_entry.take() {
             EditState::None => {}
             EditState::New(entry_data) => {
@@ -118,10 +226,132 @@ impl AppData {
                 }
             }
         }
+        true
     }
 
-    pub fn persist(&self) {
-        let _ = dump_entries(self.entries.iter().map(|e| e.data.clone().into()));
+    // Saves the entries of the open project to its file, the others to the
+    // config file.
+    pub fn persist(&mut self) {
+        let (global, projects): (Vec<_>, Vec<_>) = self
+            .entries
+            .iter()
+            .filter(|e| e.sync != SyncState::Removed)
+            .partition(|e| e.data.project.is_none());
+        // Invalid fields would be dropped from the file.
+        let invalid = global.iter().chain(projects.iter()).find_map(|e| {
+            let err = e.data.validate().err()?;
+            Some(format!("Cannot save {}: {}", e.data.alias, err))
+        });
+        if invalid.is_some() {
+            self.save_error = invalid;
+            return;
+        }
+        let mut result = dump_entries(global.iter().map(|e| e.data.clone().into()));
+        let root = self.project_root.lock().unwrap().clone();
+        if let (Ok(()), Some(root)) = (&result, root) {
+            let project = Some(root.to_string_lossy().into_owned());
+            let shadowed: Vec<String> = global.iter().map(|e| e.data.alias.clone()).collect();
+            let entries = projects
+                .iter()
+                .filter(|e| e.data.project == project)
+                .map(|e| e.data.clone().into());
+            result = dump_project(&root, entries, &shadowed);
+        }
+        self.save_error = result
+            .err()
+            .map(|err| format!("Cannot save config: {}", err));
+    }
+
+    pub fn new_entry_data(&self) -> EntryData {
+        EntryData {
+            template: self.template.clone(),
+            ..EntryData::default()
+        }
+    }
+
+    // Opens the project holding `dir`, in place of the open one.
+    pub fn open_project(&mut self, dir: &Path) {
+        let loaded = find_project(dir)
+            .map(|root| load_project(&root).map(|commands| (root, commands)))
+            .transpose();
+        match loaded {
+            Ok(Some((root, Some(commands)))) => self.set_project(root, &commands),
+            Ok(_) => {
+                self.config_error = Some(format!(
+                    "No {} found in {} or its parents",
+                    PROJECT_FILE,
+                    dir.display()
+                ))
+            }
+            Err(err) => self.config_error = Some(err.to_string()),
+        }
+    }
+
+    pub fn set_project(&mut self, root: PathBuf, commands: &Commands) {
+        let previous = self.project_root.lock().unwrap().replace(root.clone());
+        if let Some(previous) = previous.filter(|previous| *previous != root) {
+            self.merge_commands(Some(&previous), &Commands::default());
+        }
+        self.merge_commands(Some(&root), commands);
+    }
+
+    // Applies changes made to the config file, or the file of the project
+    // with the given root, on disk. Running entries keep their program going,
+    // changes apply on the next start. Commands with an alias already in use
+    // are skipped.
+    pub fn merge_commands(&mut self, project: Option<&Path>, commands: &Commands) {
+        self.config_error = None;
+        let project = project.map(|root| root.to_string_lossy().into_owned());
+        let template = Arc::new(commands.template());
+        if project.is_none() {
+            self.template = template.clone();
+        }
+        for entry in self
+            .entries
+            .iter_mut()
+            .filter(|e| e.data.project == project)
+        {
+            let running = !entry.state.is_idle();
+            match commands.get(&entry.data.alias) {
+                Some(command_entry) => {
+                    let mut data = EntryData::from_commands(
+                        commands,
+                        &template,
+                        &entry.data.alias,
+                        command_entry,
+                    );
+                    data.project = project.clone();
+                    if data != entry.data {
+                        entry.data = data;
+                        if running {
+                            entry.sync = SyncState::Outdated;
+                        }
+                    }
+                    if entry.sync == SyncState::Removed {
+                        entry.sync = SyncState::Current;
+                    }
+                }
+                None => entry.sync = SyncState::Removed,
+            }
+        }
+        self.drop_removed_entries();
+
+        for (alias, command_entry) in commands.iter() {
+            if !self.entries.iter().any(|e| &e.data.alias == alias) {
+                let mut data = EntryData::from_commands(commands, &template, alias, command_entry);
+                data.project = project.clone();
+                self.entries.push_back(Entry::new(data));
+            }
+        }
+    }
+
+    // Entries removed from the config file go away once they are not running.
+    // They are kept while editing, which refers to entries by index.
+    fn drop_removed_entries(&mut self) {
+        if self.edit_entry == EditState::None {
+            self.entries
+                .retain(|e| e.sync != SyncState::Removed || !e.state.is_idle());
+        }
     }
 
     pub fn entries_lens() -> impl Lens<AppData, (AppData, im::Vector<Entry>)> {
@@ -136,15 +366,183 @@ impl AppData {
         )
     }
 
+    pub fn log_view_lens() -> impl Lens<AppData, LogView> {
+        lens::Id.map(
+            |d: &AppData| d.log_view.clone().unwrap_or_default(),
+            |d: &mut AppData, view: LogView| {
+                if d.log_view.is_some() {
+                    d.log_view = Some(view);
+                }
+            },
+        )
+    }
+
+    // Profiles with changes to the commands, in order.
+    fn profile_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        for entry in self.entries.iter() {
+            for name in entry.data.overrides.keys() {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+        names
+    }
+
+    // Switches to the next profile, then back to no profile after the last.
+    // Running commands changed by the switch run outdated values.
+    pub fn next_profile(&mut self) {
+        let names = self.profile_names();
+        let next = match self.profile {
+            None => names.first().cloned(),
+            Some(ref profile) => names
+                .iter()
+                .skip_while(|name| *name != profile)
+                .nth(1)
+                .cloned(),
+        };
+        for entry in self.entries.iter_mut() {
+            let changed = [&self.profile, &next]
+                .iter()
+                .filter_map(|profile| profile.as_ref())
+                .any(|profile| entry.data.overrides.contains_key(profile));
+            if changed && !entry.state.is_idle() && entry.sync == SyncState::Current {
+                entry.sync = SyncState::Outdated;
+            }
+        }
+        self.profile = next;
+    }
+
+    pub fn import_lens() -> impl Lens<AppData, ImportView> {
+        lens::Id.map(
+            |d: &AppData| d.import.clone().unwrap_or_default(),
+            |d: &mut AppData, view: ImportView| {
+                if d.import.is_some() {
+                    d.import = Some(view);
+                }
+            },
+        )
+    }
+
+    // Opens the import view with the commands found in the file.
+    pub fn preview_import(&mut self, path: &Path) {
+        let mut view = ImportView {
+            source: path.display().to_string(),
+            ..ImportView::default()
+        };
+        match import_file(path) {
+            Ok(imported) => {
+                view.entries = imported
+                    .into_iter()
+                    .map(|imported| {
+                        let mut data = EntryData::from(imported);
+                        data.template = self.template.clone();
+                        let exists = self.entries.iter().any(|e| e.data.alias == data.alias);
+                        ImportEntry {
+                            selected: !exists,
+                            exists,
+                            data,
+                        }
+                    })
+                    .collect();
+                if view.entries.is_empty() {
+                    view.error = Some("No commands found".to_string());
+                }
+            }
+            Err(err) => view.error = Some(err.to_string()),
+        }
+        self.import = Some(view);
+    }
+
+    // Adds the selected commands and closes the import view, they are saved
+    // with the other entries.
+    pub fn confirm_import(&mut self) {
+        if let Some(view) = self.import.take() {
+            for imported in view.entries {
+                if imported.selected && !imported.exists {
+                    self.entries.push_back(Entry::new(imported.data));
+                }
+            }
+        }
+    }
+
+    // Fills the log view with the latest lines matching its query.
+    pub fn search_logs(&mut self) {
+        let view = match self.log_view {
+            Some(ref mut view) => view,
+            None => return,
+        };
+        let pattern = match view.query.trim() {
+            "" => None,
+            query => match Regex::new(query) {
+                Ok(pattern) => Some(pattern),
+                Err(err) => {
+                    view.error = Some(format!("Invalid pattern: {}", err));
+                    return;
+                }
+            },
+        };
+        let query = SearchQuery {
+            pattern,
+            since: None,
+            until: None,
+            limit: LogView::MAX_LINES,
+        };
+        let found = self
+            .scrollbacks
+            .lock()
+            .unwrap()
+            .get(view.alias.trim())
+            .map(|scrollback| scrollback.search(&query))
+            .unwrap_or_default();
+        view.lines = found
+            .iter()
+            .map(|line| String::from_utf8_lossy(&line.text).into_owned())
+            .collect();
+        view.error = if view.lines.is_empty() {
+            Some("No matching lines".to_string())
+        } else {
+            None
+        };
+    }
+
     pub fn handle_run_respone(&mut self, run_response: &RunResponse) {
         if let Some(entry) = self.find_entry(run_response.program_id()) {
+            if let RunResponse::Stats(_, ref stats) = *run_response {
+                // Samples can arrive after the exit.
+                if let RunState::Running(..) | RunState::Paused(..) = entry.state {
+                    entry.stats.push_back(stats.clone());
+                    if entry.stats.len() > STATS_HISTORY {
+                        entry.stats.pop_front();
+                    }
+                }
+                return;
+            }
             entry.state = entry.state.next(run_response);
-            if let RunResponse::IoError(_, ref io_error) = run_response {
-                entry.last_run_error = Some(format!("{}", io_error));
-            } else {
-                entry.last_run_error = None;
+            if let RunResponse::Started(..) | RunResponse::Exited(..) = run_response {
+                entry.stats.clear();
+            }
+            entry.port_conflict = match run_response {
+                RunResponse::PortInUse(_, ref conflict) => Some(conflict.clone()),
+                _ => None,
+            };
+            entry.last_run_error = match run_response {
+                RunResponse::IoError(_, ref io_error) => Some(format!("{}", io_error)),
+                RunResponse::PortInUse(_, ref conflict) => Some(conflict.to_string()),
+                RunResponse::Exited(_, status) => limit_exceeded(&entry.data.limits, status),
+                RunResponse::Signaled(_, signal, Err(ref err)) => {
+                    Some(format!("Cannot send {}: {}", signal_name(*signal), err))
+                }
+                RunResponse::PortOwnersKilled(_, Err(ref err)) => Some(err.to_string()),
+                _ => None,
+            };
+            // The next run picks up the new definition.
+            if entry.state.is_idle() && entry.sync == SyncState::Outdated {
+                entry.sync = SyncState::Current;
             }
         }
+        self.drop_removed_entries();
     }
 
     fn find_entry(&mut self, id: ProgramId) -> Option<&mut Entry> {
@@ -152,34 +550,76 @@ impl AppData {
             RunState::Idle(Some(state_id)) if state_id == id => Some(entry),
             RunState::Busy(state_id) if state_id == id => Some(entry),
             RunState::Running(state_id, _) if state_id == id => Some(entry),
+            RunState::Paused(state_id, _) if state_id == id => Some(entry),
             _ => None,
         })
     }
 }
 
-#[derive(Clone, Data, Lens, Eq, PartialEq)]
+#[derive(Clone, Data, Lens, PartialEq)]
 pub struct Entry {
     pub(super) data: EntryData,
     pub(super) state: RunState,
     pub(super) last_run_error: Option<String>,
+    pub(super) sync: SyncState,
+    // Recent samples while running, the oldest first.
+    pub(super) stats: im::Vector<ProcessStats>,
+    // Why the last start failed, when a port was taken.
+    pub(super) port_conflict: Option<PortConflict>,
+    // Signal to send from the entry, by name or number
+    pub(super) signal: String,
 }
 
+// Samples kept for the sparklines.
+pub(super) const STATS_HISTORY: usize = 60;
+
 impl Entry {
     pub(super) fn new(data: EntryData) -> Self {
         Self {
             data,
             state: RunState::default(),
             last_run_error: None,
+            sync: SyncState::Current,
+            stats: im::Vector::new(),
+            port_conflict: None,
+            signal: String::new(),
         }
     }
+
+    // Stops the processes holding the port, the command can then be started
+    // again. The spawner looks the owners up again, the ones of the conflict
+    // may be gone by now.
+    pub(super) fn kill_port_owners(&mut self, app_data: &AppData) {
+        let (id, conflict) = match (self.state, self.port_conflict.take()) {
+            (RunState::Idle(Some(id)), Some(conflict)) => (id, conflict),
+            _ => return,
+        };
+        self.last_run_error = None;
+        let mut tx = app_data.req_chan.clone();
+        tokio::spawn(async move {
+            let _ = tx.send(RunRequest::KillPortOwners(id, conflict.port)).await;
+        });
+    }
 }
 
-#[derive(Copy, Clone, Data, Eq, PartialEq)]
+// How an entry relates to the config file on disk.
+#[derive(Copy, Clone, Data, Debug, Eq, PartialEq)]
+pub enum SyncState {
+    Current,
+    // Changed while running, the program runs the old definition.
+    Outdated,
+    // Removed while running.
+    Removed,
+}
+
+#[derive(Copy, Clone, Data, Debug, Eq, PartialEq)]
 pub enum RunState {
     Idle(Option<ProgramId>),
     Busy(ProgramId),
     // (internal_id, PID)
     Running(ProgramId, u32),
+    // Stopped with SIGSTOP or SIGTSTP until resumed with SIGCONT.
+    Paused(ProgramId, u32),
 }
 
 impl Default for RunState {
@@ -189,9 +629,16 @@ impl Default for RunState {
 }
 
 impl RunState {
+    pub(super) fn is_idle(self) -> bool {
+        matches!(self, RunState::Idle(_))
+    }
+
     pub(super) fn next(self, res: &RunResponse) -> Self {
-        if let RunResponse::IoError(_, _) = *res {
-            return RunState::Idle(None);
+        match *res {
+            RunResponse::IoError(..) => return RunState::Idle(None),
+            // Kept for the owners of the port to be killed.
+            RunResponse::PortInUse(id, _) => return RunState::Idle(Some(id)),
+            _ => {}
         }
         match self {
             RunState::Idle(_) => self,
@@ -202,8 +649,21 @@ impl RunState {
                 RunResponse::Exited(exit_id, _) if id == exit_id => RunState::Idle(Some(id)),
                 _ => self,
             },
-            RunState::Running(id, _) => match *res {
+            RunState::Running(id, pid) => match *res {
+                RunResponse::Exited(exit_id, _) if id == exit_id => RunState::Idle(Some(id)),
+                RunResponse::Signaled(signal_id, libc::SIGSTOP, Ok(()))
+                | RunResponse::Signaled(signal_id, libc::SIGTSTP, Ok(()))
+                    if id == signal_id =>
+                {
+                    RunState::Paused(id, pid)
+                }
+                _ => self,
+            },
+            RunState::Paused(id, pid) => match *res {
                 RunResponse::Exited(exit_id, _) if id == exit_id => RunState::Idle(Some(id)),
+                RunResponse::Signaled(signal_id, libc::SIGCONT, Ok(())) if id == signal_id => {
+                    RunState::Running(id, pid)
+                }
                 _ => self,
             },
         }
@@ -216,6 +676,26 @@ pub struct EntryData {
     pub(super) command: String,
     pub(super) args: String,
     pub(super) working_dir: Option<String>,
+    pub(super) group: Option<String>,
+    // Shell words like `PORT=3000 NAME='a b'`
+    pub(super) env: String,
+    // Runs the command line with a shell, `$SHELL` unless a path is given in
+    // the config file.
+    pub(super) shell: bool,
+    // The setting of the config file, for the shell path and an explicit
+    // `shell = false` to be saved back.
+    #[data(same_fn = "PartialEq::eq")]
+    pub(super) shell_setting: Option<Shell>,
+    #[data(same_fn = "PartialEq::eq")]
+    pub(super) limits: Limits,
+    // Like `3000 3001`
+    pub(super) ports: String,
+    // Changes to the command by profile name, from the file it comes from.
+    pub(super) overrides: Arc<IndexMap<String, CommandOverride>>,
+    // Defaults and variables of the file the command comes from.
+    pub(super) template: Arc<Template>,
+    // Root of the project the command comes from, `None` for the config file.
+    pub(super) project: Option<String>,
 }
 
 impl From<(String, CommandEntry)> for EntryData {
@@ -224,12 +704,31 @@ impl From<(String, CommandEntry)> for EntryData {
             command,
             args,
             working_dir,
+            group,
+            env,
+            shell,
+            limits,
+            ports,
         } = command_entry;
+        let env = env.iter().map(|(key, value)| format!("{}={}", key, value));
         EntryData {
             alias,
             command: command,
             args: args.unwrap_or_else(String::new),
             working_dir,
+            group,
+            env: shell_words::join(env),
+            shell: shell.as_ref().and_then(Shell::path).is_some(),
+            shell_setting: shell,
+            limits,
+            ports: ports
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(" "),
+            overrides: Arc::default(),
+            template: Arc::default(),
+            project: None,
         }
     }
 }
@@ -242,21 +741,421 @@ impl Into<(String, CommandEntry)> for EntryData {
                 command: self.command,
                 args: Some(self.args).filter(|s| !s.is_empty()),
                 working_dir: self.working_dir,
+                group: self.group,
+                // Entries are validated before they are saved.
+                env: parse_env(&self.env)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect(),
+                shell: match self.shell_setting {
+                    Some(Shell::Path(path)) if self.shell => Some(Shell::Path(path)),
+                    _ if self.shell => Some(Shell::Enabled(true)),
+                    Some(Shell::Enabled(false)) => Some(Shell::Enabled(false)),
+                    _ => None,
+                },
+                limits: self.limits,
+                ports: parse_ports(&self.ports).unwrap_or_default(),
             },
         )
     }
 }
 
 impl EntryData {
-    pub(super) fn make_command(&self, id: ProgramId) -> Result<RunCommand, String> {
-        let args =
-            shell_words::split(&self.args).map_err(|_| "Invalid command arguments".to_string())?;
+    fn from_commands(
+        commands: &Commands,
+        template: &Arc<Template>,
+        alias: &str,
+        command_entry: &CommandEntry,
+    ) -> Self {
+        let mut data = EntryData::from((alias.to_string(), command_entry.clone()));
+        data.overrides = Arc::new(commands.overrides(alias));
+        data.template = template.clone();
+        data
+    }
+
+    pub(super) fn effective_group(&self) -> Option<&String> {
+        self.group
+            .as_ref()
+            .or(self.template.defaults.group.as_ref())
+    }
+
+    // Fields which are not valid as typed in the form.
+    pub(super) fn validate(&self) -> Result<(), String> {
+        parse_env(&self.env)?;
+        parse_ports(&self.ports)?;
+        Ok(())
+    }
+
+    // The command with the changes of the profile applied, then the defaults
+    // and variables of its file.
+    fn resolve(
+        &self,
+        profile: Option<&str>,
+    ) -> Result<(CommandEntry, String, Vec<String>), String> {
+        self.validate()?;
+        let (_, mut entry) = self.clone().into();
+        if let Some(command_override) = profile.and_then(|profile| self.overrides.get(profile)) {
+            command_override.apply(&mut entry);
+        }
+        let entry = self.template.resolve(&entry)?;
+        let (name, args) = entry.program()?;
+        Ok((entry, name, args))
+    }
+
+    pub(super) fn make_command(
+        &self,
+        id: ProgramId,
+        profile: Option<&str>,
+    ) -> Result<RunCommand, String> {
+        let (entry, name, args) = self.resolve(profile)?;
         Ok(RunCommand {
             id,
             alias: self.alias.clone(),
-            name: self.command.clone(),
+            name,
             args,
-            working_dir: self.working_dir.clone(),
+            working_dir: entry.working_dir,
+            env: entry.env.into_iter().collect(),
+            limits: entry.limits,
+            ports: entry.ports,
         })
     }
+
+    // How the command runs, without a profile.
+    pub(super) fn preview(&self) -> String {
+        let (entry, name, args) = match self.resolve(None) {
+            Ok(resolved) => resolved,
+            Err(err) => return err,
+        };
+        let mut preview = shell_words::join(std::iter::once(name).chain(args));
+        if let Some(dir) = entry.working_dir {
+            preview.push_str(&format!("\nin {}", dir));
+        }
+        if !entry.env.is_empty() {
+            let env = entry
+                .env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value));
+            preview.push_str(&format!("\nwith {}", shell_words::join(env)));
+        }
+        preview
+    }
+}
+
+// Why the program was killed, when it went over one of its limits. Running
+// out of memory shows up as failed allocations, which the program handles
+// itself, so only the CPU time limit is told by the signal.
+fn limit_exceeded(limits: &Limits, status: &ExitStatus) -> Option<String> {
+    match status.signal()? {
+        libc::SIGXCPU => limits
+            .cpu_seconds
+            .map(|seconds| format!("Killed by SIGXCPU, over its CPU time limit of {}s", seconds)),
+        _ => None,
+    }
+}
+
+fn parse_ports(ports: &str) -> Result<Vec<u16>, String> {
+    ports
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|port| !port.is_empty())
+        .map(|port| port.parse().map_err(|_| format!("Invalid port {}", port)))
+        .collect()
+}
+
+fn parse_env(env: &str) -> Result<Vec<(String, String)>, String> {
+    let invalid = || "Invalid environment variables".to_string();
+    shell_words::split(env)
+        .map_err(|_| invalid())?
+        .into_iter()
+        .map(|word| match word.find('=') {
+            Some(i) if i > 0 => Ok((word[..i].to_string(), word[i + 1..].to_string())),
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::program_id;
+
+    fn app_data() -> AppData {
+        let (req_chan, _) = mpsc::channel(1);
+        AppData::new(req_chan, Scrollbacks::default())
+    }
+
+    fn commands(toml: &str) -> Commands {
+        toml_edit::de::from_str(toml).unwrap()
+    }
+
+    fn entries(app_data: &AppData) -> Vec<(String, SyncState)> {
+        app_data
+            .entries
+            .iter()
+            .map(|e| (e.data.alias.clone(), e.sync))
+            .collect()
+    }
+
+    fn entry<'a>(app_data: &'a mut AppData, alias: &str) -> &'a mut Entry {
+        app_data
+            .entries
+            .iter_mut()
+            .find(|e| e.data.alias == alias)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_merge_commands() {
+        let mut app_data = app_data();
+        let web_and_db = commands(
+            r#"
+            [commands.web]
+            command = "npm"
+            [commands.db]
+            command = "postgres"
+            "#,
+        );
+        app_data.merge_commands(None, &web_and_db);
+        assert_eq!(
+            entries(&app_data),
+            vec![
+                ("web".to_string(), SyncState::Current),
+                ("db".to_string(), SyncState::Current)
+            ]
+        );
+
+        // Running entries keep going with the old definition, idle ones are
+        // dropped at once.
+        entry(&mut app_data, "web").state = RunState::Running(program_id(0), 42);
+        app_data.merge_commands(None, &commands("[commands.web]\ncommand = \"yarn\"\n"));
+        assert_eq!(
+            entries(&app_data),
+            vec![("web".to_string(), SyncState::Outdated)]
+        );
+        assert_eq!(entry(&mut app_data, "web").data.command, "yarn");
+
+        app_data.merge_commands(None, &commands("[commands.db]\ncommand = \"postgres\"\n"));
+        assert_eq!(
+            entries(&app_data),
+            vec![
+                ("web".to_string(), SyncState::Removed),
+                ("db".to_string(), SyncState::Current)
+            ]
+        );
+
+        // Added back before it exited.
+        app_data.merge_commands(None, &web_and_db);
+        assert_eq!(
+            entries(&app_data),
+            vec![
+                ("web".to_string(), SyncState::Outdated),
+                ("db".to_string(), SyncState::Current)
+            ]
+        );
+
+        // Removed entries go away once they exit.
+        app_data.merge_commands(None, &commands("[commands.db]\ncommand = \"postgres\"\n"));
+        let exited = RunResponse::Exited(program_id(0), ExitStatus::from_raw(0));
+        app_data.handle_run_respone(&exited);
+        assert_eq!(
+            entries(&app_data),
+            vec![("db".to_string(), SyncState::Current)]
+        );
+    }
+
+    #[test]
+    fn test_invalid_entry_kept_open() {
+        let mut app_data = app_data();
+        app_data.merge_commands(None, &commands("[commands.web]\ncommand = \"npm\"\n"));
+        let mut data = app_data.entries[0].data.clone();
+        data.env = "NAME='unbalanced".to_string();
+        app_data.edit_entry = EditState::Edit(0, data.clone());
+
+        assert!(!app_data.done_editing());
+        assert!(app_data.edit_entry == EditState::Edit(0, data));
+        assert!(app_data.save_error.is_some());
+        assert_eq!(app_data.entries[0].data.env, "");
+
+        let mut data = app_data.entries[0].data.clone();
+        data.ports = "8080 http".to_string();
+        app_data.edit_entry = EditState::Edit(0, data.clone());
+        app_data.save_error = None;
+
+        assert!(!app_data.done_editing());
+        assert!(app_data.edit_entry == EditState::Edit(0, data));
+        assert!(app_data.save_error.is_some());
+        assert_eq!(app_data.entries[0].data.ports, "");
+    }
+
+    #[test]
+    fn test_project_commands() {
+        let mut app_data = app_data();
+        app_data.merge_commands(None, &commands("[commands.web]\ncommand = \"npm\"\n"));
+        let project = commands(
+            r#"
+            [commands.web]
+            command = "make"
+            [commands.worker]
+            command = "celery"
+            "#,
+        );
+        app_data.set_project(PathBuf::from("/srv/shop"), &project);
+        // The global alias shadows the one of the project.
+        assert_eq!(
+            entries(&app_data),
+            vec![
+                ("web".to_string(), SyncState::Current),
+                ("worker".to_string(), SyncState::Current)
+            ]
+        );
+        assert_eq!(entry(&mut app_data, "web").data.command, "npm");
+        assert_eq!(
+            entry(&mut app_data, "worker").data.project.as_deref(),
+            Some("/srv/shop")
+        );
+
+        // Opening another project drops the commands of the previous one.
+        app_data.set_project(
+            PathBuf::from("/srv/blog"),
+            &commands("[commands.docs]\ncommand = \"mkdocs\"\n"),
+        );
+        assert_eq!(
+            entries(&app_data),
+            vec![
+                ("web".to_string(), SyncState::Current),
+                ("docs".to_string(), SyncState::Current)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_next_profile() {
+        let mut app_data = app_data();
+        app_data.merge_commands(
+            None,
+            &commands(
+                r#"
+                [commands.api]
+                command = "cargo"
+                [commands.web]
+                command = "npm"
+                [profiles.staging.api]
+                args = "run -- --staging"
+                [profiles.prod.web]
+                args = "run prod"
+                "#,
+            ),
+        );
+        entry(&mut app_data, "web").state = RunState::Running(program_id(0), 42);
+
+        app_data.next_profile();
+        assert_eq!(app_data.profile.as_deref(), Some("staging"));
+        // The running command has no staging values.
+        assert_eq!(entry(&mut app_data, "web").sync, SyncState::Current);
+
+        app_data.next_profile();
+        assert_eq!(app_data.profile.as_deref(), Some("prod"));
+        assert_eq!(entry(&mut app_data, "web").sync, SyncState::Outdated);
+
+        app_data.next_profile();
+        assert_eq!(app_data.profile, None);
+        app_data.next_profile();
+        assert_eq!(app_data.profile.as_deref(), Some("staging"));
+    }
+
+    #[test]
+    fn test_shell_setting_kept() {
+        let commands = commands(
+            r#"
+            [commands.plain]
+            command = "make"
+            shell = false
+            [commands.bash]
+            command = "make"
+            shell = "/bin/bash"
+            "#,
+        );
+        for (alias, command_entry) in commands.iter() {
+            let data = EntryData::from((alias.clone(), command_entry.clone()));
+            let (_, saved): (String, CommandEntry) = data.into();
+            assert_eq!(saved.shell, command_entry.shell);
+        }
+
+        // Unless the box is toggled.
+        let mut data =
+            EntryData::from(("plain".to_string(), commands.get("plain").unwrap().clone()));
+        data.shell = true;
+        let (_, saved): (String, CommandEntry) = data.into();
+        assert_eq!(saved.shell, Some(Shell::Enabled(true)));
+    }
+
+    #[test]
+    fn test_limit_exceeded() {
+        let limits = Limits {
+            memory_mb: Some(512),
+            cpu_seconds: Some(60),
+            ..Limits::default()
+        };
+        assert_eq!(
+            limit_exceeded(&limits, &ExitStatus::from_raw(libc::SIGXCPU)).as_deref(),
+            Some("Killed by SIGXCPU, over its CPU time limit of 60s")
+        );
+        // Crashes are not blamed on the memory limit.
+        assert_eq!(
+            limit_exceeded(&limits, &ExitStatus::from_raw(libc::SIGSEGV)),
+            None
+        );
+        assert_eq!(
+            limit_exceeded(&Limits::default(), &ExitStatus::from_raw(libc::SIGXCPU)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_run_state() {
+        let id = program_id(3);
+        let started = RunState::Busy(id).next(&RunResponse::Started(id, 42));
+        assert_eq!(started, RunState::Running(id, 42));
+
+        let stopped = RunResponse::Signaled(id, libc::SIGSTOP, Ok(()));
+        let paused = started.next(&stopped);
+        assert_eq!(paused, RunState::Paused(id, 42));
+        // Other signals leave it paused.
+        assert_eq!(
+            paused.next(&RunResponse::Signaled(id, libc::SIGHUP, Ok(()))),
+            paused
+        );
+        let failed = std::io::Error::from_raw_os_error(libc::ESRCH);
+        assert_eq!(
+            paused.next(&RunResponse::Signaled(id, libc::SIGCONT, Err(failed))),
+            paused
+        );
+        assert_eq!(
+            paused.next(&RunResponse::Signaled(id, libc::SIGCONT, Ok(()))),
+            started
+        );
+        // Signals of another program are ignored.
+        assert_eq!(
+            started.next(&RunResponse::Signaled(program_id(4), libc::SIGSTOP, Ok(()))),
+            started
+        );
+
+        // Stopping from a terminal pauses too.
+        assert_eq!(
+            started.next(&RunResponse::Signaled(id, libc::SIGTSTP, Ok(()))),
+            paused
+        );
+
+        let exited = RunResponse::Exited(id, ExitStatus::from_raw(libc::SIGTERM));
+        assert_eq!(paused.next(&exited), RunState::Idle(Some(id)));
+
+        // The id is kept, for the owners of the port to be killed.
+        let conflict = PortConflict {
+            port: 8080,
+            owners: vec![(4242, "nginx".to_string())],
+        };
+        assert_eq!(
+            RunState::Busy(id).next(&RunResponse::PortInUse(id, conflict)),
+            RunState::Idle(Some(id))
+        );
+    }
 }
@@ -1,8 +1,10 @@
 use druid::lens::{Id, LensExt};
-use druid::widget::{Align, Button, CrossAxisAlignment, Flex, FlexParams, Label, TextBox};
-use druid::{self, Lens, Target, Widget, WidgetExt};
+use druid::widget::{
+    Align, Button, Checkbox, CrossAxisAlignment, Flex, FlexParams, Label, TextBox,
+};
+use druid::{self, Color, Env, Lens, Target, Widget, WidgetExt};
 
-use super::app_data::{EntryData};
+use super::app_data::EntryData;
 use crate::constants::SAVE_TO_FILE;
 
 pub(super) fn edit_entry() -> impl Widget<EntryData> {
@@ -20,6 +22,10 @@ pub(super) fn edit_entry() -> impl Widget<EntryData> {
         .with_spacer(8.0)
         .with_child(Label::new("Arguments"))
         .with_child(TextBox::new().expand_width().lens(EntryData::args))
+        .with_spacer(4.0)
+        .with_child(
+            Checkbox::new("Run with a shell (pipes, redirects, ...)").lens(EntryData::shell),
+        )
         .with_spacer(8.0)
         .with_child(Label::new("Working Directory"))
         .with_child(
@@ -28,6 +34,27 @@ pub(super) fn edit_entry() -> impl Widget<EntryData> {
                 .lens(opt_string_lens())
                 .lens(EntryData::working_dir),
         )
+        .with_spacer(8.0)
+        .with_child(Label::new("Ports (checked before starting)"))
+        .with_child(TextBox::new().expand_width().lens(EntryData::ports))
+        .with_spacer(8.0)
+        .with_child(Label::new("Environment (KEY=value ...)"))
+        .with_child(TextBox::new().expand_width().lens(EntryData::env))
+        .with_spacer(8.0)
+        .with_child(Label::new("Group"))
+        .with_child(
+            TextBox::new()
+                .expand_width()
+                .lens(opt_string_lens())
+                .lens(EntryData::group),
+        )
+        .with_spacer(8.0)
+        .with_child(Label::new("Resolved Command"))
+        .with_child(
+            Label::new(|data: &EntryData, _env: &Env| data.preview())
+                .with_text_color(Color::grey(0.6))
+                .with_text_size(12.0),
+        )
         .with_spacer(16.0)
         .with_flex_child(
             Button::new("Done")
@@ -1,11 +1,14 @@
+use druid::kurbo::BezPath;
 use druid::widget::{
-    Button, Container, CrossAxisAlignment, Flex, Label, MainAxisAlignment, Padding, ViewSwitcher,
+    Button, Container, CrossAxisAlignment, Either, Flex, Label, MainAxisAlignment, Padding,
+    Painter, SizedBox, TextBox, ViewSwitcher,
 };
-use druid::{self, Color, Env, Widget, WidgetExt};
+use druid::{self, Color, Env, RenderContext, Widget, WidgetExt};
 
-use super::app_data::{AppData, EditState, Entry, RunState};
+use super::app_data::{AppData, EditState, Entry, LogView, RunState, SyncState, STATS_HISTORY};
 use crate::constants::SAVE_TO_FILE;
-use crate::model::{ProgramIdGen, RunRequest};
+use crate::model::{parse_signal, ProcessStats, ProgramId, ProgramIdGen, RunRequest};
+use crate::proc_stats::{format_bytes, local_url};
 
 pub(super) fn entry() -> impl Widget<(AppData, Entry)> {
     Padding::new(
@@ -13,7 +16,13 @@ pub(super) fn entry() -> impl Widget<(AppData, Entry)> {
         Container::new(
             Flex::row()
                 .main_axis_alignment(MainAxisAlignment::SpaceBetween)
-                .with_flex_child(entry_data().lens(druid::lens!((AppData, Entry), 1)), 2.0)
+                .with_flex_child(
+                    Flex::column()
+                        .cross_axis_alignment(CrossAxisAlignment::Start)
+                        .with_child(entry_data().lens(druid::lens!((AppData, Entry), 1)))
+                        .with_child(kill_owner_button()),
+                    2.0,
+                )
                 .with_flex_child(actions(), 1.0),
         )
         .padding((8.0, 4.0))
@@ -30,6 +39,9 @@ fn entry_data() -> impl Widget<Entry> {
                 let state = entry.state;
                 match state {
                     RunState::Running(_, pid) => format!("{} (PID: {})", &entry.data.alias, pid),
+                    RunState::Paused(_, pid) => {
+                        format!("{} (PID: {}, paused)", &entry.data.alias, pid)
+                    }
                     _ => entry.data.alias.clone(),
                 }
             })
@@ -38,11 +50,26 @@ fn entry_data() -> impl Widget<Entry> {
         )
         .with_spacer(4.0)
         .with_child(
-            Label::new(|entry: &Entry, _env: &Env| {
-                format!("{} {}", &entry.data.command, &entry.data.args)
-            })
+            Label::new(
+                |entry: &Entry, _env: &Env| match entry.data.effective_group() {
+                    Some(group) => {
+                        format!("[{}] {} {}", group, &entry.data.command, &entry.data.args)
+                    }
+                    None => format!("{} {}", &entry.data.command, &entry.data.args),
+                },
+            )
             .with_text_size(14.0),
         )
+        .with_child(stats())
+        .with_child(
+            Label::new(|entry: &Entry, _env: &Env| match entry.sync {
+                SyncState::Current => String::new(),
+                SyncState::Outdated => "outdated definition, restart to apply".to_string(),
+                SyncState::Removed => "removed from config".to_string(),
+            })
+            .with_text_color(Color::rgb(0.9, 0.6, 0.1))
+            .with_text_size(10.0),
+        )
         .with_child(
             Label::new(|entry: &Entry, _env: &Env| {
                 if let Some(ref error) = entry.last_run_error {
@@ -56,12 +83,122 @@ fn entry_data() -> impl Widget<Entry> {
         )
 }
 
+// Offered when another process holds a port of the entry.
+fn kill_owner_button() -> impl Widget<(AppData, Entry)> {
+    Either::new(
+        |(_, entry): &(AppData, Entry), _env| {
+            entry
+                .port_conflict
+                .as_ref()
+                .filter(|conflict| !conflict.owners.is_empty())
+                .is_some()
+        },
+        Button::new("Kill owner")
+            .on_click(|_ctx, (app_data, entry): &mut (AppData, Entry), _env| {
+                entry.kill_port_owners(app_data)
+            })
+            .fix_size(96.0, 24.0),
+        SizedBox::empty(),
+    )
+}
+
+// Usage of a running entry, with sparklines of the recent CPU and memory.
+fn stats() -> impl Widget<Entry> {
+    Flex::row()
+        .with_child(sparkline(|stats| stats.cpu_percent, 100.0).fix_size(60.0, 12.0))
+        .with_spacer(4.0)
+        .with_child(sparkline(|stats| stats.rss_bytes as f64, 0.0).fix_size(60.0, 12.0))
+        .with_spacer(8.0)
+        .with_child(
+            Label::new(|entry: &Entry, _env: &Env| match entry.stats.last() {
+                Some(stats) => format!(
+                    "CPU {:.1}%  RSS {}  {} threads  {} ports",
+                    stats.cpu_percent,
+                    format_bytes(stats.rss_bytes),
+                    stats.threads,
+                    stats.ports.len()
+                ),
+                None => String::new(),
+            })
+            .with_text_size(10.0),
+        )
+        .with_spacer(8.0)
+        .with_child(ports())
+}
+
+// Links opening the listening ports in the browser.
+fn ports() -> impl Widget<Entry> {
+    ViewSwitcher::new(
+        |entry: &Entry, _env| {
+            entry
+                .stats
+                .last()
+                .map(|stats| stats.ports.clone())
+                .unwrap_or_default()
+        },
+        |ports, _entry, _env| {
+            let mut links = Flex::row();
+            for port in ports.iter().copied() {
+                links.add_child(
+                    Label::new(local_url(port))
+                        .with_text_color(Color::rgb(0.3, 0.6, 1.0))
+                        .with_text_size(10.0)
+                        .on_click(move |_ctx, _entry: &mut Entry, _env| open_url(&local_url(port))),
+                );
+                links.add_spacer(8.0);
+            }
+            Box::new(links)
+        },
+    )
+}
+
+// Waits for xdg-open off the UI thread, so that it is reaped.
+fn open_url(url: &str) {
+    let url = url.to_string();
+    tokio::task::spawn_blocking(move || {
+        match std::process::Command::new("xdg-open").arg(&url).status() {
+            Ok(status) if !status.success() => log::error!("Cannot open {}: {}", url, status),
+            Ok(_) => {}
+            Err(err) => log::error!("Cannot open {}: {}", url, err),
+        }
+    });
+}
+
+// Lines up the samples from the right, scaled to the largest one or `max`.
+fn sparkline(value: fn(&ProcessStats) -> f64, max: f64) -> impl Widget<Entry> {
+    Painter::new(move |ctx, entry: &Entry, _env| {
+        if entry.stats.len() < 2 {
+            return;
+        }
+        let size = ctx.size();
+        let max = entry.stats.iter().map(value).fold(max, f64::max);
+        let step = size.width / (STATS_HISTORY - 1) as f64;
+        let offset = STATS_HISTORY - entry.stats.len();
+        let mut path = BezPath::new();
+        for (i, stats) in entry.stats.iter().enumerate() {
+            let x = (offset + i) as f64 * step;
+            let y = if max > 0.0 {
+                size.height * (1.0 - value(stats) / max)
+            } else {
+                size.height
+            };
+            if i == 0 {
+                path.move_to((x, y));
+            } else {
+                path.line_to((x, y));
+            }
+        }
+        ctx.stroke(path, &Color::rgb(0.3, 0.7, 0.9), 1.0);
+    })
+}
+
 fn actions() -> impl Widget<(AppData, Entry)> {
     ViewSwitcher::new(
         |(_, entry): &(_, Entry), _env| entry.state,
         |selector, _data, _env| match *selector {
             RunState::Idle(..) => Box::new(idle_buttons()),
-            RunState::Running(..) => Box::new(kill_button()),
+            RunState::Running(..) => Box::new(running_buttons()),
+            RunState::Paused(..) => Box::new(paused_buttons()),
             _ => Box::new(Label::new("waiting...")),
         },
     )
@@ -77,7 +214,7 @@ fn idle_buttons() -> impl Widget<(AppData, Entry)> {
                 }
                 _ => return,
             };
-            let run_command = match entry.data.make_command(id) {
+            let run_command = match entry.data.make_command(id, app_data.profile.as_deref()) {
                 Ok(run_command) => run_command,
                 Err(message) => {
                     entry.last_run_error = Some(message);
@@ -122,6 +259,8 @@ fn idle_buttons() -> impl Widget<(AppData, Entry)> {
         .fix_size(32.0, 32.0);
 
     Flex::row()
+        .with_child(logs_button())
+        .with_spacer(12.0)
         .with_child(delete)
         .with_spacer(12.0)
         .with_child(edit)
@@ -129,11 +268,84 @@ fn idle_buttons() -> impl Widget<(AppData, Entry)> {
         .with_child(start)
 }
 
+fn logs_button() -> impl Widget<(AppData, Entry)> {
+    Button::new("Logs")
+        .on_click(|_ctx, (app_data, entry): &mut (AppData, Entry), _env| {
+            app_data.log_view = Some(LogView::new(entry.data.alias.clone()));
+            app_data.search_logs();
+        })
+        .fix_size(56.0, 32.0)
+}
+
+fn running_buttons() -> impl Widget<(AppData, Entry)> {
+    let pause = Button::new("Pause")
+        .on_click(|_ctx, (app_data, entry): &mut (AppData, Entry), _env| {
+            if let RunState::Running(id, _) = entry.state {
+                send_signal(app_data, id, libc::SIGSTOP);
+            }
+        })
+        .fix_size(72.0, 32.0);
+
+    // Any other signal, eg. HUP for programs reloading their config on it.
+    let send = Button::new("Send")
+        .on_click(|_ctx, (app_data, entry): &mut (AppData, Entry), _env| {
+            let id = match entry.state {
+                RunState::Running(id, _) => id,
+                _ => return,
+            };
+            match parse_signal(&entry.signal) {
+                Ok(signal) => send_signal(app_data, id, signal),
+                Err(message) => entry.last_run_error = Some(message),
+            }
+        })
+        .fix_size(56.0, 32.0);
+
+    Flex::row()
+        .with_child(logs_button())
+        .with_spacer(12.0)
+        .with_child(pause)
+        .with_spacer(12.0)
+        .with_child(
+            TextBox::new()
+                .fix_width(64.0)
+                .lens(Entry::signal)
+                .lens(druid::lens!((AppData, Entry), 1)),
+        )
+        .with_child(send)
+        .with_spacer(12.0)
+        .with_child(kill_button())
+}
+
+fn paused_buttons() -> impl Widget<(AppData, Entry)> {
+    let resume = Button::new("Resume")
+        .on_click(|_ctx, (app_data, entry): &mut (AppData, Entry), _env| {
+            if let RunState::Paused(id, _) = entry.state {
+                send_signal(app_data, id, libc::SIGCONT);
+            }
+        })
+        .fix_size(72.0, 32.0);
+
+    Flex::row()
+        .with_child(logs_button())
+        .with_spacer(12.0)
+        .with_child(resume)
+        .with_spacer(12.0)
+        .with_child(kill_button())
+}
+
+// The state follows once the spawner reports the signal as sent.
+fn send_signal(app_data: &AppData, id: ProgramId, signal: i32) {
+    let mut tx = app_data.req_chan.clone();
+    tokio::spawn(async move {
+        let _ = tx.send(RunRequest::Signal(id, signal, None)).await;
+    });
+}
+
 fn kill_button() -> impl Widget<(AppData, Entry)> {
     Button::new("Kill")
         .on_click(|_ctx, (app_data, entry): &mut (AppData, Entry), _env| {
             let id = match entry.state {
-                RunState::Running(id, _) => id,
+                RunState::Running(id, _) | RunState::Paused(id, _) => id,
                 _ => return,
             };
             let kill_request = RunRequest::Kill(id);
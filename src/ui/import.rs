@@ -0,0 +1,63 @@
+use druid::widget::{Button, Checkbox, CrossAxisAlignment, Flex, Label, List, Scroll};
+use druid::{self, Color, Env, Widget, WidgetExt};
+
+use super::app_data::{AppData, ImportEntry, ImportView};
+use crate::constants::SAVE_TO_FILE;
+
+pub(super) fn import_view() -> impl Widget<AppData> {
+    let entries = Scroll::new(List::new(|| {
+        Flex::row()
+            .with_child(Checkbox::new("").lens(ImportEntry::selected))
+            .with_spacer(8.0)
+            .with_child(
+                Label::new(|imported: &ImportEntry, _env: &Env| {
+                    let data = &imported.data;
+                    let line = format!("{}: {} {}", data.alias, data.command, data.args);
+                    if imported.exists {
+                        format!("{} (alias in use)", line.trim_end())
+                    } else {
+                        line
+                    }
+                })
+                .with_text_size(14.0),
+            )
+            .padding((0.0, 2.0))
+    }))
+    .vertical()
+    .lens(ImportView::entries);
+
+    let buttons = Flex::row()
+        .with_child(
+            Button::new("Cancel")
+                .on_click(|_ctx, app_data: &mut AppData, _env| app_data.import = None)
+                .fix_size(72.0, 32.0),
+        )
+        .with_spacer(8.0)
+        .with_child(
+            Button::new("Add")
+                .on_click(|ctx, app_data: &mut AppData, _env| {
+                    app_data.confirm_import();
+                    ctx.submit_command(SAVE_TO_FILE, None);
+                })
+                .fix_size(72.0, 32.0),
+        );
+
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(
+            Label::new(|view: &ImportView, _env: &Env| format!("Import from {}", view.source))
+                .with_text_size(20.0)
+                .lens(AppData::import_lens()),
+        )
+        .with_spacer(8.0)
+        .with_child(
+            Label::new(|view: &ImportView, _env: &Env| view.error.clone().unwrap_or_default())
+                .with_text_color(Color::rgb(0.8, 0.1, 0.1))
+                .with_text_size(10.0)
+                .lens(AppData::import_lens()),
+        )
+        .with_flex_child(entries.lens(AppData::import_lens()), 1.0)
+        .with_spacer(8.0)
+        .with_child(buttons)
+        .padding((4.0, 8.0))
+}
@@ -0,0 +1,51 @@
+use druid::widget::{Button, CrossAxisAlignment, Flex, Label, List, Scroll, TextBox};
+use druid::{self, Color, Env, Widget, WidgetExt};
+
+use super::app_data::{AppData, LogView};
+
+pub(super) fn log_view() -> impl Widget<AppData> {
+    let search_bar = Flex::row()
+        .with_child(
+            Button::new("Back")
+                .on_click(|_ctx, app_data: &mut AppData, _env| app_data.log_view = None)
+                .fix_size(72.0, 32.0),
+        )
+        .with_spacer(8.0)
+        .with_flex_child(
+            TextBox::new()
+                .expand_width()
+                .lens(LogView::query)
+                .lens(AppData::log_view_lens()),
+            1.0,
+        )
+        .with_spacer(8.0)
+        .with_child(
+            Button::new("Search")
+                .on_click(|_ctx, app_data: &mut AppData, _env| app_data.search_logs())
+                .fix_size(72.0, 32.0),
+        );
+
+    let lines = Scroll::new(List::new(|| {
+        Label::new(|line: &String, _env: &Env| line.clone()).with_text_size(12.0)
+    }))
+    .vertical()
+    .lens(LogView::lines);
+
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(
+            Label::new(|view: &LogView, _env: &Env| format!("Outputs of {}", view.alias))
+                .with_text_size(20.0)
+                .lens(AppData::log_view_lens()),
+        )
+        .with_spacer(8.0)
+        .with_child(search_bar)
+        .with_child(
+            Label::new(|view: &LogView, _env: &Env| view.error.clone().unwrap_or_default())
+                .with_text_color(Color::rgb(0.8, 0.1, 0.1))
+                .with_text_size(10.0)
+                .lens(AppData::log_view_lens()),
+        )
+        .with_flex_child(lines.lens(AppData::log_view_lens()), 1.0)
+        .padding((4.0, 8.0))
+}
@@ -1,16 +1,23 @@
+use druid::commands::{OPEN_FILE, SHOW_OPEN_PANEL};
 use druid::lens::LensExt;
-use druid::widget::{Button, CrossAxisAlignment, Flex, List, Scroll, ViewSwitcher};
-use druid::{AppDelegate, Command, DelegateCtx, Env, Target, Widget, WidgetExt};
+use druid::widget::{Button, CrossAxisAlignment, Flex, Label, List, Scroll, ViewSwitcher};
+use druid::{
+    AppDelegate, Color, Command, DelegateCtx, Env, FileDialogOptions, Target, Widget, WidgetExt,
+};
 
 pub mod app_data;
 
 mod edit;
 mod entry;
+mod import;
+mod log_view;
 mod response_handler;
 
-use self::app_data::{AppData, EditState, EntryData};
+use self::app_data::{AppData, EditState, OpenDialog};
 use self::edit::edit_entry;
 use self::entry::entry;
+use self::import::import_view;
+use self::log_view::log_view;
 use self::response_handler::ResponseHandler;
 use crate::constants::SAVE_TO_FILE;
 
@@ -32,24 +39,47 @@ impl AppDelegate<AppData> for Delegate {
         _env: &Env,
     ) -> bool {
         if let Some(_) = cmd.get(SAVE_TO_FILE) {
-            data.done_editing();
-            let _ = data.persist();
+            if data.done_editing() {
+                data.persist();
+            }
+        }
+        if let Some(file_info) = cmd.get(OPEN_FILE) {
+            match data.open_dialog.take() {
+                Some(OpenDialog::Import) => data.preview_import(file_info.path()),
+                Some(OpenDialog::Project) => data.open_project(file_info.path()),
+                None => {}
+            }
         }
         true
     }
 }
 
+pub fn window_title(app_data: &AppData, _env: &Env) -> String {
+    match app_data.profile {
+        Some(ref profile) => format!("Top Spin ({})", profile),
+        None => "Top Spin".to_string(),
+    }
+}
+
 pub fn ui_builder() -> impl Widget<AppData> {
     let mut root = Flex::column();
     let child = ViewSwitcher::new(
-        |app_data: &AppData, _| app_data.edit_entry.map_to(()),
+        |app_data: &AppData, _| {
+            (
+                app_data.edit_entry.map_to(()),
+                app_data.import.is_some(),
+                app_data.log_view.is_some(),
+            )
+        },
         |selector, _data, _env| match selector {
-            EditState::New(_) | EditState::Edit(..) => Box::new(
+            (EditState::New(_), ..) | (EditState::Edit(..), ..) => Box::new(
                 edit_entry()
                     .lens(EditState::data())
                     .lens(AppData::edit_entry),
             ),
-            EditState::None => Box::new(list_view()),
+            (EditState::None, true, _) => Box::new(import_view()),
+            (EditState::None, false, true) => Box::new(log_view()),
+            (EditState::None, false, false) => Box::new(list_view()),
         },
     );
 
@@ -61,13 +91,58 @@ fn list_view() -> impl Widget<AppData> {
     Flex::column()
         .cross_axis_alignment(CrossAxisAlignment::Start)
         .with_child(
-            Button::new("New Command")
-                .on_click(|_ctx, app_data: &mut AppData, _env| {
-                    app_data.edit_entry = EditState::New(EntryData::default())
-                })
-                .fix_height(32.0)
-                .padding((3.0, 0.0))
-                .expand_width(),
+            Flex::row()
+                .with_flex_child(
+                    Button::new("New Command")
+                        .on_click(|_ctx, app_data: &mut AppData, _env| {
+                            app_data.edit_entry = EditState::New(app_data.new_entry_data())
+                        })
+                        .fix_height(32.0)
+                        .padding((3.0, 0.0))
+                        .expand_width(),
+                    1.0,
+                )
+                .with_child(
+                    Button::new(|app_data: &AppData, _env: &Env| match app_data.profile {
+                        Some(ref profile) => format!("Profile: {}", profile),
+                        None => "No Profile".to_string(),
+                    })
+                    .on_click(|_ctx, app_data: &mut AppData, _env| app_data.next_profile())
+                    .fix_size(140.0, 32.0)
+                    .padding((3.0, 0.0)),
+                )
+                .with_child(
+                    Button::new("Import")
+                        .on_click(|ctx, app_data: &mut AppData, _env| {
+                            let options = FileDialogOptions::new();
+                            app_data.open_dialog = Some(OpenDialog::Import);
+                            ctx.submit_command(SHOW_OPEN_PANEL.with(options), None);
+                        })
+                        .fix_size(80.0, 32.0)
+                        .padding((3.0, 0.0)),
+                )
+                .with_child(
+                    Button::new("Open Project")
+                        .on_click(|ctx, app_data: &mut AppData, _env| {
+                            let options = FileDialogOptions::new().select_directories();
+                            app_data.open_dialog = Some(OpenDialog::Project);
+                            ctx.submit_command(SHOW_OPEN_PANEL.with(options), None);
+                        })
+                        .fix_size(120.0, 32.0)
+                        .padding((3.0, 0.0)),
+                ),
+        )
+        .with_child(
+            // Saving fails too while the config file has errors.
+            Label::new(
+                |app_data: &AppData, _env: &Env| match app_data.config_error {
+                    Some(ref err) => format!("Config file has errors: {}", err),
+                    None => app_data.save_error.clone().unwrap_or_default(),
+                },
+            )
+            .with_text_color(Color::rgb(0.8, 0.1, 0.1))
+            .with_text_size(12.0)
+            .padding((3.0, 0.0)),
         )
         .with_child(
             Scroll::new(List::new(entry))
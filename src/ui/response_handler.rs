@@ -2,7 +2,7 @@ use druid::widget::{Controller, Widget};
 use druid::{Env, Event, EventCtx};
 
 use super::app_data::AppData;
-use crate::constants::RUN_RESPONSES;
+use crate::constants::{CONFIG_CHANGED, CONFIG_ERROR, RUN_RESPONSES};
 
 pub struct ResponseHandler;
 
@@ -24,11 +24,19 @@ where
         data: &mut AppData,
         env: &Env,
     ) {
-        let run_response = match event {
-            Event::Command(cmd) if cmd.is(RUN_RESPONSES) => cmd.get_unchecked(RUN_RESPONSES),
+        match event {
+            Event::Command(cmd) if cmd.is(RUN_RESPONSES) => {
+                data.handle_run_respone(cmd.get_unchecked(RUN_RESPONSES))
+            }
+            Event::Command(cmd) if cmd.is(CONFIG_CHANGED) => {
+                let (ref project, ref commands) = *cmd.get_unchecked(CONFIG_CHANGED);
+                data.merge_commands(project.as_deref(), commands)
+            }
+            Event::Command(cmd) if cmd.is(CONFIG_ERROR) => {
+                data.config_error = Some(cmd.get_unchecked(CONFIG_ERROR).clone())
+            }
             _ => return child.event(ctx, event, data, env),
-        };
-        data.handle_run_respone(run_response);
+        }
         ctx.request_paint();
     }
 }